@@ -152,11 +152,43 @@ fn main() {
     if enabled!("SQL") {
         write!(f, r#"
 #define rustg_sql_connect_pool(host, port, user, pass, db, timeout, min_threads, max_threads) call(RUST_G, "sql_connect_pool")(host, "[port]", user, pass, db, "[timeout]", "[min_threads]", "[max_threads]")
+#define rustg_sql_connect_pool_options(host, port, user, pass, db, timeout, min_threads, max_threads, options) call(RUST_G, "sql_connect_pool")(host, "[port]", user, pass, db, "[timeout]", "[min_threads]", "[max_threads]", options)
+#define rustg_sql_connect_async(host, port, user, pass, db, timeout, min_threads, max_threads) call(RUST_G, "sql_connect_async")(host, "[port]", user, pass, db, "[timeout]", "[min_threads]", "[max_threads]")
+#define rustg_sql_connect_async_options(host, port, user, pass, db, timeout, min_threads, max_threads, options) call(RUST_G, "sql_connect_async")(host, "[port]", user, pass, db, "[timeout]", "[min_threads]", "[max_threads]", options)
+#define rustg_sql_test_connection(host, port, user, pass, db, timeout) call(RUST_G, "sql_test_connection")(host, "[port]", user, pass, db, "[timeout]")
 #define rustg_sql_query_async(query, params) call(RUST_G, "sql_query_async")(query, params)
 #define rustg_sql_query_blocking(query, params) call(RUST_G, "sql_query_blocking")(query, params)
+#define rustg_sql_query_async_options(query, params, options) call(RUST_G, "sql_query_async")(query, params, options)
+#define rustg_sql_query_blocking_options(query, params, options) call(RUST_G, "sql_query_blocking")(query, params, options)
+#define rustg_sql_query_json_params(query, params) call(RUST_G, "sql_query_json_params")(query, params)
+#define rustg_sql_query_json_params_async(query, params) call(RUST_G, "sql_query_json_params_async")(query, params)
+#define RUSTG_SQL_DELIMITED_ROW ascii2text(30)
+#define RUSTG_SQL_DELIMITED_CELL ascii2text(31)
+#define rustg_sql_param_count(query) call(RUST_G, "sql_param_count")(query)
+#define rustg_sql_show(kind, arg) call(RUST_G, "sql_show")(kind, arg)
+#define rustg_sql_maintain(command, table) call(RUST_G, "sql_maintain")(command, table)
+#define rustg_sql_table_size(table) call(RUST_G, "sql_table_size")(table)
+#define rustg_sql_load_reference(name, query) call(RUST_G, "sql_load_reference")(name, query)
+#define rustg_sql_reference_reload(name) call(RUST_G, "sql_reference_reload")(name)
+#define rustg_sql_reference_lookup(name, key_column, key_value) call(RUST_G, "sql_reference_lookup")(name, key_column, "[key_value]")
+#define rustg_sql_server_status(names) call(RUST_G, "sql_server_status")(names)
+#define rustg_sql_server_status_scope(names, scope) call(RUST_G, "sql_server_status")(names, scope)
+#define rustg_sql_batch_update_async(query, param_sets) call(RUST_G, "sql_batch_update_async")(query, param_sets)
+#define rustg_sql_batch_update_blocking(query, param_sets) call(RUST_G, "sql_batch_update_blocking")(query, param_sets)
+#define rustg_sql_insert_open(table, columns) call(RUST_G, "sql_insert_open")(table, columns)
+#define rustg_sql_insert_open_options(table, columns, options) call(RUST_G, "sql_insert_open")(table, columns, options)
+#define rustg_sql_insert_push(handle, param_sets) call(RUST_G, "sql_insert_push")(handle, param_sets)
+#define rustg_sql_insert_finish(handle) call(RUST_G, "sql_insert_finish")(handle)
 /proc/rustg_sql_connected() return call(RUST_G, "sql_connected")()
 /proc/rustg_sql_disconnect_pool() return call(RUST_G, "sql_disconnect_pool")()
+/proc/rustg_sql_stats() return call(RUST_G, "sql_stats")()
+/proc/rustg_sql_capabilities() return call(RUST_G, "sql_capabilities")()
+/proc/rustg_sql_last_error() return call(RUST_G, "sql_last_error")()
+/proc/rustg_sql_jobs_status() return call(RUST_G, "sql_jobs_status")()
 #define rustg_sql_check_query(job_id) call(RUST_G, "sql_check_query")("[job_id]")
+#define rustg_sql_abort(job_id) call(RUST_G, "sql_abort")("[job_id]")
+#define rustg_sql_wait_query(job_id, timeout_ms) call(RUST_G, "sql_wait_query")("[job_id]", "[timeout_ms]")
+#define rustg_sql_drain_results(job_ids) call(RUST_G, "sql_drain_results")(job_ids)
 "#).unwrap();
     }
 }
@@ -27,7 +27,7 @@ fn get_algorithm(string: &str) -> Result<Algorithm> {
     Ok(algorithm)
 }
 
-fn string_hash(algorithm: &str, string: &str) -> Result<String> {
+pub(crate) fn string_hash(algorithm: &str, string: &str) -> Result<String> {
     let algorithm = get_algorithm(algorithm)?;
     let digest = crypto_hash::digest(algorithm, string.as_bytes());
 
@@ -3,16 +3,41 @@ use std::sync::mpsc;
 use std::thread;
 use std::collections::hash_map::{HashMap, Entry};
 use std::cell::RefCell;
+use std::time::{Duration, Instant};
 
 struct Job {
     rx: mpsc::Receiver<Output>,
     handle: thread::JoinHandle<()>,
+    // Output already received by `poll`, waiting to be collected.
+    output: Option<Output>,
+    started: Instant,
+}
+
+impl Job {
+    // Whether the job has finished, keeping its output if so.
+    fn poll(&mut self) -> bool {
+        if self.output.is_none() {
+            self.output = match self.rx.try_recv() {
+                Ok(result) => Some(result),
+                Err(mpsc::TryRecvError::Disconnected) => Some(JOB_PANICKED.to_owned()),
+                Err(mpsc::TryRecvError::Empty) => None,
+            };
+        }
+        self.output.is_some()
+    }
+}
+
+// A snapshot of the job store. Each running job has its own thread.
+pub struct Status {
+    pub running: usize,
+    pub unclaimed: usize,
+    pub oldest: Option<Duration>,
 }
 
 type Output = String;
 type JobId = String;
 
-const NO_RESULTS_YET: &str = "NO RESULTS YET";
+pub const NO_RESULTS_YET: &str = "NO RESULTS YET";
 const NO_SUCH_JOB: &str = "NO SUCH JOB";
 const JOB_PANICKED: &str = "JOB PANICKED";
 
@@ -23,23 +48,25 @@ struct Jobs {
 }
 
 impl Jobs {
-    fn start<F: FnOnce() -> Output + Send + 'static>(&mut self, f: F) -> JobId {
+    fn start<F: FnOnce(JobId) -> Output + Send + 'static>(&mut self, f: F) -> JobId {
         let (tx, rx) = mpsc::channel();
-        let handle = thread::spawn(move || {
-            let _ = tx.send(f());
-        });
         let id = self.next_job.to_string();
         self.next_job += 1;
-        self.map.insert(id.clone(), Job { rx, handle });
+        let job_id = id.clone();
+        let handle = thread::spawn(move || {
+            let _ = tx.send(f(job_id));
+        });
+        self.map.insert(id.clone(), Job { rx, handle, output: None, started: Instant::now() });
         id
     }
 
     fn check(&mut self, id: &str) -> Output {
-        let entry = match self.map.entry(id.to_owned()) {
+        let mut entry = match self.map.entry(id.to_owned()) {
             Entry::Occupied(occupied) => occupied,
             Entry::Vacant(_) => return NO_SUCH_JOB.to_owned(),
         };
-        let result = match entry.get().rx.try_recv() {
+        let output = entry.get_mut().output.take();
+        let result = match output.map(Ok).unwrap_or_else(|| entry.get().rx.try_recv()) {
             Ok(result) => result,
             Err(mpsc::TryRecvError::Disconnected) => JOB_PANICKED.to_owned(),
             Err(mpsc::TryRecvError::Empty) => return NO_RESULTS_YET.to_owned(),
@@ -47,6 +74,41 @@ impl Jobs {
         let _ = entry.remove().handle.join();
         result
     }
+
+    fn wait(&mut self, id: &str, timeout: Duration) -> Output {
+        let mut entry = match self.map.entry(id.to_owned()) {
+            Entry::Occupied(occupied) => occupied,
+            Entry::Vacant(_) => return NO_SUCH_JOB.to_owned(),
+        };
+        let output = entry.get_mut().output.take();
+        let result = match output.map(Ok).unwrap_or_else(|| entry.get().rx.recv_timeout(timeout)) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Disconnected) => JOB_PANICKED.to_owned(),
+            Err(mpsc::RecvTimeoutError::Timeout) => return NO_RESULTS_YET.to_owned(),
+        };
+        let _ = entry.remove().handle.join();
+        result
+    }
+
+    fn is_finished(&mut self, id: &str) -> Option<bool> {
+        self.map.get_mut(id).map(Job::poll)
+    }
+
+    fn status(&mut self) -> Status {
+        let mut status = Status { running: 0, unclaimed: 0, oldest: None };
+        for job in self.map.values_mut() {
+            if job.poll() {
+                status.unclaimed += 1;
+            } else {
+                status.running += 1;
+            }
+            let age = job.started.elapsed();
+            if status.oldest.map_or(true, |oldest| age > oldest) {
+                status.oldest = Some(age);
+            }
+        }
+        status
+    }
 }
 
 thread_local! {
@@ -54,9 +116,28 @@ thread_local! {
 }
 
 pub fn start<F: FnOnce() -> Output + Send + 'static>(f: F) -> JobId {
+    start_with_id(move |_| f())
+}
+
+// Like `start`, but tells the job its own id.
+pub fn start_with_id<F: FnOnce(JobId) -> Output + Send + 'static>(f: F) -> JobId {
     JOBS.with(|jobs| jobs.borrow_mut().start(f))
 }
 
 pub fn check(id: &str) -> String {
     JOBS.with(|jobs| jobs.borrow_mut().check(id))
 }
+
+pub fn wait(id: &str, timeout: Duration) -> String {
+    JOBS.with(|jobs| jobs.borrow_mut().wait(id, timeout))
+}
+
+// Whether the job has finished, without collecting its output; None if
+// there's no such job.
+pub fn is_finished(id: &str) -> Option<bool> {
+    JOBS.with(|jobs| jobs.borrow_mut().is_finished(id))
+}
+
+pub fn status() -> Status {
+    JOBS.with(|jobs| jobs.borrow_mut().status())
+}
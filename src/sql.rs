@@ -1,27 +1,234 @@
 use jobs;
 use mysql::consts::ColumnFlags;
 use mysql::consts::ColumnType::*;
-use mysql::{OptsBuilder, Params, Pool};
+use mysql::{Column, Conn, Opts, OptsBuilder, Params, Pool, PooledConn};
 use serde_json::map::Map;
 use serde_json::{json, Number};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
-use std::sync::RwLock;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 lazy_static! {
     static ref POOL: RwLock<Option<Pool>> = RwLock::new(None);
+    // Settings given when the current pool was connected.
+    static ref SETTINGS: RwLock<Arc<ConnectOptions>> = Default::default();
+    // The most recent query error, for sql_last_error.
+    static ref LAST_ERROR: RwLock<Option<serde_json::Value>> = RwLock::new(None);
+    static ref ABORTS: Mutex<Aborts> = Default::default();
+    // Set up from the rate_limit connect option.
+    static ref RATE_LIMITER: Mutex<Option<TokenBucket>> = Mutex::new(None);
+    // Tables loaded by sql_load_reference, by name.
+    static ref REFERENCES: RwLock<HashMap<String, ReferenceTable>> = Default::default();
+    // Open sql_insert_open sessions, by handle.
+    static ref INSERT_SESSIONS: Mutex<HashMap<String, InsertSession>> = Default::default();
 }
 
+// Async query jobs that haven't finished, which are the only jobs
+// sql_abort can stop; those that are running, by job id, with the id of the
+// connection running them; and those aborted before they got a connection.
+#[derive(Default)]
+struct Aborts {
+    queries: HashSet<String>,
+    running: HashMap<String, u32>,
+    cancelled: HashSet<String>,
+}
+
+static SPILL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+// Connects started by sql_connect_async that haven't finished yet.
+static CONNECTS_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+// How long queries kept their connection checked out of the pool, reported by
+// sql_stats. Times are in microseconds.
+static HOLD_COUNT: AtomicU64 = AtomicU64::new(0);
+static HOLD_TOTAL_US: AtomicU64 = AtomicU64::new(0);
+static HOLD_MAX_US: AtomicU64 = AtomicU64::new(0);
+// Queries turned away by the rate limit.
+static RATE_LIMITED: AtomicU64 = AtomicU64::new(0);
+// Callers blocked in get_conn waiting for a free connection.
+static POOL_WAITING: AtomicUsize = AtomicUsize::new(0);
+
+// Refills at `rate` tokens a second and holds up to a second's worth, so
+// short bursts go through but a sustained flood is held to the rate.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+    // Queries let through this second, for reporting the current rate.
+    window_start: Instant,
+    window_count: u64,
+    last_window_count: u64,
+}
+
+// Caps the wait TokenBucket::take computes, in seconds, so a tiny rate
+// can't overflow a Duration. Any wait this long is turned away by
+// `throttle` anyway.
+const MAX_RATE_LIMIT_WAIT: f64 = 3600.0;
+
+impl TokenBucket {
+    fn new(rate: f64) -> TokenBucket {
+        let now = Instant::now();
+        TokenBucket {
+            rate,
+            tokens: rate.max(1.0),
+            refilled: now,
+            window_start: now,
+            window_count: 0,
+            last_window_count: 0,
+        }
+    }
+
+    // Takes a token, returning how long until it would really have been
+    // available: zero if there was one to spare.
+    fn take(&mut self) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate.max(1.0)) - 1.0;
+        self.refilled = now;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            // Capped, as a tiny rate would wait longer than a Duration can
+            // hold. `min` also turns a NaN into the cap.
+            Duration::from_secs_f64((-self.tokens / self.rate).min(MAX_RATE_LIMIT_WAIT))
+        }
+    }
+
+    fn give_back(&mut self) {
+        self.tokens += 1.0;
+    }
+
+    fn admitted(&mut self) {
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            self.last_window_count = if elapsed >= Duration::from_secs(2) {
+                0
+            } else {
+                self.window_count
+            };
+            self.window_count = 0;
+            self.window_start = Instant::now();
+        }
+        self.window_count += 1;
+    }
+
+    // Queries let through in the last whole second.
+    fn recent_rate(&self) -> u64 {
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= Duration::from_secs(2) {
+            0
+        } else if elapsed >= Duration::from_secs(1) {
+            self.window_count
+        } else {
+            self.last_window_count
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RateLimited;
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("query rate limit exceeded")
+    }
+}
+
+impl Error for RateLimited {}
+
 // HELPER FUNCTIONS
-fn err_to_json(e: Box<dyn Error>) -> String {
-    json!({
+
+// Sorts errors into broad categories so callers can decide what to do about
+// them without matching on message text. Server errors also carry their code.
+fn error_category(e: &(dyn Error + 'static)) -> (&'static str, Option<u16>) {
+    match e.downcast_ref::<mysql::Error>() {
+        Some(mysql::Error::MySqlError(e)) => match e.code {
+            // Bad credentials or missing grants; retrying won't help.
+            1044 | 1045 | 1698 => ("auth", Some(e.code)),
+            code => ("server", Some(code)),
+        },
+        Some(e) if is_link_failure(e) => ("connection", None),
+        Some(_) => ("driver", None),
+        None if e.is::<RateLimited>() => ("rate_limited", None),
+        None => ("other", None),
+    }
+}
+
+// Whether the error means the link to the server failed, as opposed to the
+// driver rejecting what it was asked to do, such as a wrong number of
+// params. The driver's own is_connectivity_error counts both.
+fn is_link_failure(e: &mysql::Error) -> bool {
+    use mysql::DriverError::*;
+
+    match e {
+        mysql::Error::IoError(_)
+        | mysql::Error::CodecError(_)
+        | mysql::Error::TlsError(_)
+        | mysql::Error::TlsHandshakeError(_) => true,
+        mysql::Error::DriverError(e) => matches!(
+            e,
+            ConnectTimeout | CouldNotConnect(_) | Timeout | PacketOutOfSync | UnexpectedPacket
+        ),
+        _ => false,
+    }
+}
+
+fn error_json(e: Box<dyn Error>) -> serde_json::Value {
+    let (category, code) = error_category(&*e);
+    let mut result = json!({
         "status": "err",
-        "data": &e.to_string()
-    })
-    .to_string()
+        "data": &e.to_string(),
+        "category": category,
+    });
+    if let Some(code) = code {
+        result["code"] = json!(code);
+    }
+    result
+}
+
+fn err_to_json(e: Box<dyn Error>) -> String {
+    error_json(e).to_string()
+}
+
+// Whether `s` is a number written the one way it would be printed, so
+// binding it as a number can't change what it means. Leading zeros and
+// trailing fractional zeros suggest a code or a fixed-point value instead.
+fn numeric_string(s: &str) -> Option<mysql::Value> {
+    if let Ok(i) = s.parse::<i64>() {
+        if i.to_string() == s {
+            return Some(mysql::Value::Int(i));
+        }
+    } else if let Ok(u) = s.parse::<u64>() {
+        if u.to_string() == s {
+            return Some(mysql::Value::UInt(u));
+        }
+    } else if s
+        .bytes()
+        .all(|c| c.is_ascii_digit() || c == b'.' || c == b'-')
+    {
+        if let Ok(f) = s.parse::<f64>() {
+            if f.is_finite() && f.to_string() == s {
+                return Some(mysql::Value::Float(f));
+            }
+        }
+    }
+    None
 }
 
-fn json_to_mysql(val: serde_json::Value) -> mysql::Value {
+// How JSON params are turned into values to bind.
+#[derive(Clone, Copy)]
+struct Binding {
+    // See `ConnectOptions::coerce_numeric_strings`.
+    coerce_numeric: bool,
+    // Bind objects and arrays as JSON text, for JSON columns, instead of
+    // treating arrays as bytes and objects as NULL.
+    json_documents: bool,
+}
+
+fn json_to_mysql(val: serde_json::Value, binding: Binding) -> mysql::Value {
     match val {
         serde_json::Value::Bool(b) => mysql::Value::UInt(b as u64),
         serde_json::Value::Number(i) => {
@@ -35,7 +242,13 @@ fn json_to_mysql(val: serde_json::Value) -> mysql::Value {
                 mysql::Value::NULL
             }
         }
-        serde_json::Value::String(s) => mysql::Value::Bytes(s.into()),
+        serde_json::Value::String(s) => match numeric_string(&s) {
+            Some(number) if binding.coerce_numeric => number,
+            _ => mysql::Value::Bytes(s.into()),
+        },
+        serde_json::Value::Array(_) | serde_json::Value::Object(_) if binding.json_documents => {
+            mysql::Value::Bytes(val.to_string().into())
+        }
         serde_json::Value::Array(a) => mysql::Value::Bytes(
             a.into_iter()
                 .map(|x| {
@@ -51,157 +264,2337 @@ fn json_to_mysql(val: serde_json::Value) -> mysql::Value {
     }
 }
 
-fn array_to_params(params: Vec<serde_json::Value>) -> Params {
+fn array_to_params(params: Vec<serde_json::Value>, binding: Binding) -> Params {
     if params.is_empty() {
         Params::Empty
     } else {
-        Params::Positional(params.into_iter().map(json_to_mysql).collect())
+        Params::Positional(
+            params
+                .into_iter()
+                .map(|val| json_to_mysql(val, binding))
+                .collect(),
+        )
     }
 }
 
-fn object_to_params(params: Map<std::string::String, serde_json::Value>) -> Params {
+fn object_to_params(
+    params: Map<std::string::String, serde_json::Value>,
+    binding: Binding,
+) -> Params {
     if params.is_empty() {
         Params::Empty
     } else {
         Params::Named(
             params
                 .into_iter()
-                .map(|(key, val)| (key, json_to_mysql(val)))
+                .map(|(key, val)| (key, json_to_mysql(val, binding)))
                 .collect(),
         )
     }
 }
 
-fn params_from_json(params: &str) -> Params {
-    match serde_json::from_str(params) {
-        Ok(serde_json::Value::Object(o)) => object_to_params(o),
-        Ok(serde_json::Value::Array(a)) => array_to_params(a),
-        _ => Params::Empty,
-    }
-}
+// An object binds named params and an array positional ones. A query can
+// only use one style: the driver has no way to bind both at once, so the
+// `{"__positional__": [..], "__named__": {..}}` form is refused up front
+// instead of silently binding half of it.
+fn value_to_params(params: serde_json::Value, binding: Binding) -> Result<Params, Box<dyn Error>> {
+    Ok(match params {
+        serde_json::Value::Object(ref o)
+            if o.contains_key("__positional__") || o.contains_key("__named__") =>
+        {
+            return Err("positional and named params can't be mixed in one query; \
+                 use `?` or `:name` placeholders throughout"
+                .into())
+        }
+        serde_json::Value::Object(o) => object_to_params(o, binding),
+        serde_json::Value::Array(a) => array_to_params(a, binding),
+        _ => Params::Empty,
+    })
+}
+
+fn settings() -> Result<Arc<ConnectOptions>, Box<dyn Error>> {
+    Ok(SETTINGS.read()?.clone())
+}
+
+// Results larger than the configured threshold are written to a file in the
+// spill directory and replaced by a reference to it, so a huge result can't
+// blow through BYOND's string limits. The caller owns and deletes the file.
+fn spill_if_oversized(output: String, settings: &ConnectOptions) -> Result<String, Box<dyn Error>> {
+    match settings.spill_threshold {
+        Some(threshold) if output.len() > threshold => {}
+        _ => return Ok(output),
+    }
+    let dir = match settings.spill_dir {
+        Some(ref dir) => PathBuf::from(dir),
+        None => std::env::temp_dir(),
+    };
+    let path = dir.join(format!(
+        "rustg-sql-{}-{}.json",
+        std::process::id(),
+        SPILL_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&path, &output)?;
+    Ok(json!({
+        "status": "ok",
+        "spilled": true,
+        "path": path.to_string_lossy(),
+        "size": output.len(),
+    })
+    .to_string())
+}
+
+// Checks out a connection from the pool, or None if it is offline.
+fn get_conn() -> Result<Option<PooledConn>, Box<dyn Error>> {
+    let p = POOL.read()?;
+    match &*p {
+        Some(pool) => {
+            // Here rather than in each caller, so the limit covers everything
+            // that talks to the server through the pool.
+            throttle(settings()?.rate_limit_mode)?;
+            POOL_WAITING.fetch_add(1, Ordering::SeqCst);
+            let conn = pool.get_conn();
+            POOL_WAITING.fetch_sub(1, Ordering::SeqCst);
+            Ok(Some(conn?))
+        }
+        None => Ok(None),
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct QueryOptions {
+    // Emit SET columns as an array of their members rather than the
+    // comma-joined string the server returns.
+    set_as_array: bool,
+    // Include the server's info string ("Records: 3  Duplicates: 0 ...")
+    // for statements that produce one.
+    info: bool,
+    // Include a breakdown of where the time went; see `Timings`. Only JSON
+    // output has room for it.
+    timings: bool,
+    // Cap text values at this many characters, marking where it happened.
+    max_text_length: Option<usize>,
+    // Values for `{{name}}` identifier slots in the query text.
+    identifiers: HashMap<String, String>,
+    // Run the query under this role (MySQL 8 `SET ROLE`), restoring the
+    // connection's default roles before it goes back to the pool.
+    role: Option<String>,
+    // Run the query with this as the current database (`USE`), switching
+    // back to the connect-time one before it goes back to the pool.
+    database: Option<String>,
+    // Session variables to set for the query, like `group_concat_max_len`,
+    // put back to what they were before it goes back to the pool.
+    session_variables: BTreeMap<String, serde_json::Value>,
+    // How spatial values are emitted.
+    geometry: GeometryFormat,
+    // Include the query text (and job id, for async queries) in errors.
+    error_context: Option<ErrorContext>,
+    // Post-processing applied to the text values of the named columns.
+    transforms: HashMap<String, Transform>,
+    // A query run on the same connection straight after the main one, its
+    // rows returned as `returned`. Used to read back generated keys that
+    // last_insert_id can't report, such as UUIDs set by a trigger.
+    returning: Option<String>,
+    // Emit integers too big to survive a round trip through a double, in
+    // cells and the affected count, as strings.
+    big_ints_as_strings: bool,
+    // How successful results are written out; see `to_delimited`.
+    output: OutputFormat,
+    // Read the named columns as bytes or as text, whatever their binary flag
+    // says. For schemas where the flag can't be trusted.
+    column_kinds: HashMap<String, ColumnKind>,
+    // Parse the text values of the named columns as JSON, embedding the
+    // result rather than the string. For schemas that keep JSON in text
+    // columns. Values that don't parse are left as they were and listed in
+    // `json_errors`.
+    json_columns: HashSet<String>,
+    // Values emitted in place of NULL for the named columns.
+    null_defaults: HashMap<String, serde_json::Value>,
+    // Return rows as an object of arrays keyed by this column's value.
+    group_by: Option<String>,
+    // Return rows as an object keyed by this column's value, for columns
+    // whose values are unique. Should they not be, the last row wins.
+    key_by: Option<String>,
+    // Fail rather than return any column as a byte array, for callers that
+    // only expect text.
+    reject_binary: bool,
+    // Read the rows off the wire and throw them away, returning only the
+    // affected count and last insert id. For statements run for their side
+    // effects that still produce a result set.
+    discard_rows: bool,
+    // How many result sets with columns the statement should produce, such
+    // as the SELECTs in a stored procedure; a CALL's trailing status doesn't
+    // count. A different number is a warning or an error, per the mismatch
+    // setting.
+    expected_result_sets: Option<usize>,
+    result_set_mismatch: Mismatch,
+    // Write each row's value of a blob column to its own file in the
+    // connect-time `blob_dir`, returning the path in its place.
+    blob_file: Option<BlobFile>,
+    // Include the warnings the statement raised, from `SHOW WARNINGS`.
+    warnings: bool,
+    // With this set, named placeholders the params leave out are bound to
+    // the value given here, or NULL, instead of failing the query.
+    param_defaults: Option<Map<String, serde_json::Value>>,
+    // Let statements that aren't reads be retried on a fresh connection
+    // after the first one is lost. Only safe for statements that can be
+    // run twice, since the server may have run it before the link dropped.
+    retry_writes: bool,
+    // Set by sql_query_json_params rather than given as an option.
+    #[serde(skip)]
+    json_params: bool,
+    // Include each result column's name and type, which is the only way to
+    // know them when no rows come back.
+    columns: bool,
+    // Include a hash of the result columns' names and types, which changes
+    // when the shape of the result does.
+    schema_hash: bool,
+}
+
+#[derive(Deserialize)]
+struct BlobFile {
+    // The blob column to write out.
+    column: String,
+    // The column whose value names each row's file. Names may only use
+    // letters, digits, `_`, `-` and `.`, and can't start with a `.`.
+    name_column: String,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Mismatch {
+    Warn,
+    Error,
+}
+
+impl Default for Mismatch {
+    fn default() -> Mismatch {
+        Mismatch::Warn
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ColumnKind {
+    Binary,
+    Text,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Transform {
+    Lowercase,
+    Uppercase,
+    Trim,
+    // Hex SHA-256 digest; needs the `hash` feature.
+    #[cfg(feature = "hash")]
+    Hash,
+}
+
+impl Transform {
+    fn apply(self, text: &str) -> String {
+        match self {
+            Transform::Lowercase => text.to_lowercase(),
+            Transform::Uppercase => text.to_uppercase(),
+            Transform::Trim => text.trim().to_owned(),
+            #[cfg(feature = "hash")]
+            Transform::Hash => ::hash::string_hash("sha256", text).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum ErrorContext {
+    // The query exactly as given.
+    Full,
+    // The query with its literal values replaced by `?`.
+    Redacted,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum GeometryFormat {
+    // `POINT(1 2)`
+    Wkt,
+    // `{"type": "Point", "coordinates": [1, 2]}`
+    GeoJson,
+    // `[1, 2]`, the GeoJSON coordinates on their own
+    Coords,
+}
+
+impl Default for GeometryFormat {
+    fn default() -> GeometryFormat {
+        GeometryFormat::Wkt
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    Json,
+    Delimited,
+}
+
+impl Default for OutputFormat {
+    fn default() -> OutputFormat {
+        OutputFormat::Json
+    }
+}
+
+fn options_from_json(options: &str) -> Result<QueryOptions, Box<dyn Error>> {
+    if options.is_empty() {
+        Ok(QueryOptions::default())
+    } else {
+        Ok(serde_json::from_str(options)?)
+    }
+}
+
+// Quotes a table or column name for splicing into query text. Qualified names
+// like `db.table` are quoted part by part. Anything outside the characters
+// MySQL allows in unquoted identifiers is rejected rather than escaped.
+fn quote_identifier(ident: &str) -> Result<String, Box<dyn Error>> {
+    let mut quoted = String::with_capacity(ident.len() + 2);
+    for (i, part) in ident.split('.').enumerate() {
+        if part.is_empty()
+            || !part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+        {
+            return Err(format!("invalid identifier: {:?}", ident).into());
+        }
+        if i > 0 {
+            quoted.push('.');
+        }
+        quoted.push('`');
+        quoted.push_str(part);
+        quoted.push('`');
+    }
+    Ok(quoted)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Span {
+    Code,
+    Literal,
+    Identifier,
+    Comment,
+}
+
+// Splits query text into runs of plain code, string literals, quoted
+// identifiers and comments, so it can be inspected without being fooled by
+// punctuation that happens to be inside quotes.
+fn split_query(query: &str) -> Vec<(Span, &str)> {
+    let bytes = query.as_bytes();
+    let mut spans = Vec::new();
+    let mut code_start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let (kind, end) = match bytes[i] {
+            b'\'' | b'"' => (Span::Literal, quoted_end(bytes, i, true)),
+            b'`' => (Span::Identifier, quoted_end(bytes, i, false)),
+            b'#' => (Span::Comment, line_end(bytes, i)),
+            // `--` only starts a comment when followed by whitespace.
+            b'-' if bytes.get(i + 1) == Some(&b'-')
+                && bytes.get(i + 2).map_or(true, |c| c.is_ascii_whitespace()) =>
+            {
+                (Span::Comment, line_end(bytes, i))
+            }
+            // `/*! ... */` is run by MySQL, so it's code rather than a
+            // comment.
+            b'/' if bytes.get(i + 1) == Some(&b'*') && bytes.get(i + 2) != Some(&b'!') => {
+                let end = query[i + 2..]
+                    .find("*/")
+                    .map_or(bytes.len(), |end| i + 2 + end + 2);
+                (Span::Comment, end)
+            }
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        if code_start < i {
+            spans.push((Span::Code, &query[code_start..i]));
+        }
+        spans.push((kind, &query[i..end]));
+        i = end;
+        code_start = end;
+    }
+    if code_start < bytes.len() {
+        spans.push((Span::Code, &query[code_start..]));
+    }
+    spans
+}
+
+// Finds the end of the quoted run starting at `start`. A doubled quote
+// character stands for itself; string literals also allow backslash escapes.
+fn quoted_end(bytes: &[u8], start: usize, backslash_escapes: bool) -> usize {
+    let quote = bytes[start];
+    let mut i = start + 1;
+    while i < bytes.len() {
+        if backslash_escapes && bytes[i] == b'\\' {
+            i += 2;
+        } else if bytes[i] != quote {
+            i += 1;
+        } else if bytes.get(i + 1) == Some(&quote) {
+            i += 2;
+        } else {
+            return i + 1;
+        }
+    }
+    bytes.len()
+}
+
+fn line_end(bytes: &[u8], start: usize) -> usize {
+    bytes[start..]
+        .iter()
+        .position(|&c| c == b'\n')
+        .map_or(bytes.len(), |end| start + end)
+}
+
+// Whether a `;` separates two statements, ignoring semicolons inside quotes
+// or comments and a single trailing one.
+fn has_multiple_statements(query: &str) -> bool {
+    let mut seen_separator = false;
+    for (kind, text) in split_query(query) {
+        match kind {
+            Span::Comment => {}
+            Span::Code => {
+                for c in text.chars() {
+                    if c == ';' {
+                        seen_separator = true;
+                    } else if seen_separator && !c.is_whitespace() {
+                        return true;
+                    }
+                }
+            }
+            Span::Literal | Span::Identifier => {
+                if seen_separator {
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+// Whether the statement only reads, going by its first keyword, so that
+// running it twice does no harm.
+fn is_read_only(query: &str) -> bool {
+    let keyword = split_query(query)
+        .into_iter()
+        .filter(|&(kind, _)| kind != Span::Comment)
+        .find_map(|(kind, text)| match kind {
+            Span::Code => text.split_whitespace().next().map(Some),
+            _ => Some(None),
+        })
+        .flatten()
+        .unwrap_or("");
+    let keyword = keyword.trim_start_matches('(');
+    ["SELECT", "SHOW", "DESCRIBE", "DESC", "EXPLAIN"]
+        .iter()
+        .any(|read| keyword.eq_ignore_ascii_case(read))
+}
+
+// Replaces string and numeric literals, and comments, with `?` so query
+// text can be logged without leaking the values in it.
+fn redact_query(query: &str) -> String {
+    let mut output = String::with_capacity(query.len());
+    for (kind, text) in split_query(query) {
+        match kind {
+            Span::Literal => {
+                // Drop the prefix of hex, bit and national literals like
+                // `x'DEAD'` too, so the whole literal reads as one value.
+                let mut chars = output.chars().rev();
+                if let Some('x') | Some('X') | Some('b') | Some('B') | Some('n') | Some('N') =
+                    chars.next()
+                {
+                    if !chars
+                        .next()
+                        .map_or(false, |c| c.is_alphanumeric() || c == '_' || c == '$')
+                    {
+                        output.pop();
+                    }
+                }
+                output.push('?');
+            }
+            Span::Code => {
+                let mut in_word = false;
+                let mut in_number = false;
+                for c in text.chars() {
+                    let word_char = c.is_alphanumeric() || c == '_' || c == '$';
+                    if in_number && (word_char || c == '.') {
+                        continue;
+                    }
+                    in_number = !in_word && c.is_ascii_digit();
+                    in_word = word_char && !in_number;
+                    output.push(if in_number { '?' } else { c });
+                }
+            }
+            Span::Identifier => output.push_str(text),
+            // Comments can hold anything, so their text isn't kept. Executable
+            // `/*! ... */` comments are code, and redacted as such.
+            Span::Comment => output.push_str("/* ? */"),
+        }
+    }
+    output
+}
+
+// Positional params of the form `{"__in__": [..]}` are lists for an `IN (?)`
+// clause. Their `?` is rewritten into one placeholder per element and the
+// elements are bound in its place, so the params after it still line up.
+fn expand_in_lists<'a>(
+    query: &'a str,
+    params: serde_json::Value,
+    binding: Binding,
+) -> Result<(Cow<'a, str>, Params), Box<dyn Error>> {
+    let is_list = |param: &serde_json::Value| param.get("__in__").is_some();
+    let mut params = match params {
+        serde_json::Value::Array(a) if a.iter().any(is_list) => a.into_iter().enumerate(),
+        other => return Ok((Cow::Borrowed(query), value_to_params(other, binding)?)),
+    };
+
+    let mut output = String::with_capacity(query.len());
+    let mut bound = Vec::new();
+    for (kind, text) in split_query(query) {
+        if kind != Span::Code {
+            output.push_str(text);
+            continue;
+        }
+        for c in text.chars() {
+            if c != '?' {
+                output.push(c);
+                continue;
+            }
+            match params.next() {
+                Some((i, serde_json::Value::Object(mut o))) if o.contains_key("__in__") => {
+                    let list = match o.remove("__in__") {
+                        Some(serde_json::Value::Array(list)) => list,
+                        _ => {
+                            return Err(
+                                format!("__in__ for parameter {} is not an array", i + 1).into()
+                            )
+                        }
+                    };
+                    // `IN ()` is a syntax error, so there's nothing sensible to
+                    // expand an empty list into.
+                    if list.is_empty() {
+                        return Err(format!("__in__ list for parameter {} is empty", i + 1).into());
+                    }
+                    let count = list.len();
+                    bound.extend(list);
+                    output.push_str(&vec!["?"; count].join(", "));
+                }
+                Some((_, param)) => {
+                    output.push('?');
+                    bound.push(param);
+                }
+                None => output.push('?'),
+            }
+        }
+    }
+    // Leftovers are passed through so the driver reports the count mismatch.
+    bound.extend(params.map(|(_, param)| param));
+    Ok((Cow::Owned(output), array_to_params(bound, binding)))
+}
+
+// Names of the `:name` placeholders in the query, outside quotes and
+// comments, in order of appearance.
+fn named_placeholders(query: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    for (kind, text) in split_query(query) {
+        if kind != Span::Code {
+            continue;
+        }
+        let bytes = text.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            // `::` isn't a placeholder, nor is `:=` assignment.
+            if bytes[i] == b':' && bytes.get(i + 1) == Some(&b':') {
+                i += 2;
+                continue;
+            }
+            let start = i + 1;
+            let is_name_start = |c: u8| c.is_ascii_alphabetic() || c == b'_';
+            if bytes[i] == b':' && bytes.get(start).map_or(false, |&c| is_name_start(c)) {
+                let end = bytes[start..]
+                    .iter()
+                    .position(|&c| !(c.is_ascii_alphanumeric() || c == b'_'))
+                    .map_or(bytes.len(), |len| start + len);
+                names.push(&text[start..end]);
+                i = end;
+            } else {
+                i += 1;
+            }
+        }
+    }
+    names
+}
+
+// Binds named placeholders missing from `params` to their value in
+// `defaults`, or NULL if it has none. Params the query doesn't use are left
+// alone, and are ignored when binding. Positional params are untouched.
+fn fill_named_defaults(
+    query: &str,
+    params: serde_json::Value,
+    defaults: &Map<String, serde_json::Value>,
+) -> serde_json::Value {
+    let mut params = match params {
+        serde_json::Value::Object(o) => o,
+        serde_json::Value::Null => Map::new(),
+        other => return other,
+    };
+    for name in named_placeholders(query) {
+        if !params.contains_key(name) {
+            let default = defaults
+                .get(name)
+                .cloned()
+                .unwrap_or(serde_json::Value::Null);
+            params.insert(name.to_owned(), default);
+        }
+    }
+    serde_json::Value::Object(params)
+}
+
+// Replaces each `{{name}}` slot in the query with the quoted identifier
+// supplied for it. Slots inside string literals, quoted identifiers and
+// comments are left as they are. Values still go through normal parameter
+// binding.
+fn substitute_identifiers(
+    query: &str,
+    identifiers: &HashMap<String, String>,
+) -> Result<String, Box<dyn Error>> {
+    let mut output = String::with_capacity(query.len());
+    for (kind, text) in split_query(query) {
+        if kind != Span::Code {
+            output.push_str(text);
+            continue;
+        }
+        let mut rest = text;
+        while let Some(start) = rest.find("{{") {
+            let end = match rest[start..].find("}}") {
+                Some(end) => start + end,
+                None => return Err("unterminated identifier slot".into()),
+            };
+            let name = rest[start + 2..end].trim();
+            let ident = match identifiers.get(name) {
+                Some(ident) => ident,
+                None => {
+                    return Err(format!("no identifier supplied for slot {{{{{}}}}}", name).into())
+                }
+            };
+            output.push_str(&rest[..start]);
+            output.push_str(&quote_identifier(ident)?);
+            rest = &rest[end + 2..];
+        }
+        output.push_str(rest);
+    }
+    Ok(output)
+}
+
+// MySQL sends spatial values as a 4-byte SRID followed by standard WKB.
+enum Geometry {
+    Point(f64, f64),
+    LineString(Vec<(f64, f64)>),
+    Polygon(Vec<Vec<(f64, f64)>>),
+    MultiPoint(Vec<Geometry>),
+    MultiLineString(Vec<Geometry>),
+    MultiPolygon(Vec<Geometry>),
+    GeometryCollection(Vec<Geometry>),
+}
+
+struct WkbReader<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> WkbReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Some(head)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let b = self.take(4)?;
+        let bytes = [b[0], b[1], b[2], b[3]];
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    fn f64(&mut self) -> Option<f64> {
+        let b = self.take(8)?;
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(b);
+        Some(if self.little_endian {
+            f64::from_le_bytes(bytes)
+        } else {
+            f64::from_be_bytes(bytes)
+        })
+    }
+
+    fn point(&mut self) -> Option<(f64, f64)> {
+        Some((self.f64()?, self.f64()?))
+    }
+
+    fn points(&mut self) -> Option<Vec<(f64, f64)>> {
+        let count = self.u32()?;
+        (0..count).map(|_| self.point()).collect()
+    }
+
+    fn geometries(&mut self) -> Option<Vec<Geometry>> {
+        let count = self.u32()?;
+        (0..count).map(|_| self.geometry()).collect()
+    }
+
+    fn geometry(&mut self) -> Option<Geometry> {
+        // Every nested geometry carries its own byte order marker.
+        self.little_endian = self.take(1)?[0] == 1;
+        Some(match self.u32()? {
+            1 => {
+                let (x, y) = self.point()?;
+                Geometry::Point(x, y)
+            }
+            2 => Geometry::LineString(self.points()?),
+            3 => {
+                let count = self.u32()?;
+                Geometry::Polygon((0..count).map(|_| self.points()).collect::<Option<_>>()?)
+            }
+            4 => Geometry::MultiPoint(self.geometries()?),
+            5 => Geometry::MultiLineString(self.geometries()?),
+            6 => Geometry::MultiPolygon(self.geometries()?),
+            7 => Geometry::GeometryCollection(self.geometries()?),
+            _ => return None,
+        })
+    }
+}
+
+fn parse_geometry(data: &[u8]) -> Option<Geometry> {
+    if data.len() < 4 {
+        return None;
+    }
+    WkbReader {
+        data: &data[4..],
+        little_endian: true,
+    }
+    .geometry()
+}
+
+fn wkt_points(points: &[(f64, f64)]) -> String {
+    let coords: Vec<String> = points.iter().map(|(x, y)| format!("{} {}", x, y)).collect();
+    format!("({})", coords.join(","))
+}
+
+impl Geometry {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Geometry::Point(..) => "Point",
+            Geometry::LineString(_) => "LineString",
+            Geometry::Polygon(_) => "Polygon",
+            Geometry::MultiPoint(_) => "MultiPoint",
+            Geometry::MultiLineString(_) => "MultiLineString",
+            Geometry::MultiPolygon(_) => "MultiPolygon",
+            Geometry::GeometryCollection(_) => "GeometryCollection",
+        }
+    }
+
+    // The body of the WKT text, without the type name.
+    fn wkt_body(&self) -> String {
+        match self {
+            Geometry::Point(x, y) => format!("({} {})", x, y),
+            Geometry::LineString(points) => wkt_points(points),
+            Geometry::Polygon(rings) => {
+                let rings: Vec<String> = rings.iter().map(|ring| wkt_points(ring)).collect();
+                format!("({})", rings.join(","))
+            }
+            Geometry::MultiPoint(parts)
+            | Geometry::MultiLineString(parts)
+            | Geometry::MultiPolygon(parts) => {
+                let parts: Vec<String> = parts.iter().map(Geometry::wkt_body).collect();
+                format!("({})", parts.join(","))
+            }
+            Geometry::GeometryCollection(parts) => {
+                let parts: Vec<String> = parts.iter().map(Geometry::to_wkt).collect();
+                format!("({})", parts.join(","))
+            }
+        }
+    }
+
+    fn to_wkt(&self) -> String {
+        format!("{}{}", self.type_name().to_uppercase(), self.wkt_body())
+    }
+
+    fn coordinates(&self) -> serde_json::Value {
+        fn point(x: f64, y: f64) -> serde_json::Value {
+            json!([x, y])
+        }
+        fn points(points: &[(f64, f64)]) -> serde_json::Value {
+            points.iter().map(|&(x, y)| point(x, y)).collect()
+        }
+        match self {
+            Geometry::Point(x, y) => point(*x, *y),
+            Geometry::LineString(line) => points(line),
+            Geometry::Polygon(rings) => rings.iter().map(|ring| points(ring)).collect(),
+            Geometry::MultiPoint(parts)
+            | Geometry::MultiLineString(parts)
+            | Geometry::MultiPolygon(parts)
+            | Geometry::GeometryCollection(parts) => {
+                parts.iter().map(Geometry::coordinates).collect()
+            }
+        }
+    }
+
+    fn to_geojson(&self) -> serde_json::Value {
+        match self {
+            Geometry::GeometryCollection(parts) => json!({
+                "type": self.type_name(),
+                "geometries": parts.iter().map(Geometry::to_geojson).collect::<Vec<_>>(),
+            }),
+            _ => json!({
+                "type": self.type_name(),
+                "coordinates": self.coordinates(),
+            }),
+        }
+    }
+
+    fn to_json(&self, format: GeometryFormat) -> serde_json::Value {
+        match format {
+            GeometryFormat::Wkt => serde_json::Value::String(self.to_wkt()),
+            GeometryFormat::GeoJson => self.to_geojson(),
+            GeometryFormat::Coords => self.coordinates(),
+        }
+    }
+}
+
+// Measures query phases only when timings were requested, so the common path
+// doesn't pay for the clock reads.
+struct Stopwatch(Option<Instant>);
+
+impl Stopwatch {
+    fn start(enabled: bool) -> Stopwatch {
+        Stopwatch(if enabled { Some(Instant::now()) } else { None })
+    }
+
+    // Returns the time since the previous lap.
+    fn lap(&mut self) -> Duration {
+        match self.0 {
+            Some(ref mut last) => {
+                let now = Instant::now();
+                let elapsed = now - *last;
+                *last = now;
+                elapsed
+            }
+            None => Duration::default(),
+        }
+    }
+}
+
+fn duration_to_ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+fn is_text_column(col: &Column) -> bool {
+    match col.column_type() {
+        MYSQL_TYPE_VARCHAR | MYSQL_TYPE_STRING | MYSQL_TYPE_VAR_STRING => true,
+        MYSQL_TYPE_BLOB | MYSQL_TYPE_LONG_BLOB | MYSQL_TYPE_MEDIUM_BLOB | MYSQL_TYPE_TINY_BLOB => {
+            !col.flags().contains(ColumnFlags::BINARY_FLAG)
+        }
+        _ => false,
+    }
+}
+
+// Cuts `text` down to `limit` characters plus an ellipsis, returning whether
+// anything was removed.
+fn truncate_text(text: &mut String, limit: usize) -> bool {
+    match text.char_indices().nth(limit) {
+        Some((end, _)) => {
+            text.truncate(end);
+            text.push_str("...");
+            true
+        }
+        None => false,
+    }
+}
+
+// The largest integer a double holds exactly. Some JSON parsers, BYOND's
+// included, read every number as one.
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+fn int_to_json(i: i64, options: &QueryOptions) -> serde_json::Value {
+    if options.big_ints_as_strings
+        && (i > MAX_SAFE_INTEGER as i64 || i < -(MAX_SAFE_INTEGER as i64))
+    {
+        serde_json::Value::String(i.to_string())
+    } else {
+        serde_json::Value::Number(Number::from(i))
+    }
+}
+
+fn uint_to_json(u: u64, options: &QueryOptions) -> serde_json::Value {
+    if options.big_ints_as_strings && u > MAX_SAFE_INTEGER {
+        serde_json::Value::String(u.to_string())
+    } else {
+        serde_json::Value::Number(Number::from(u))
+    }
+}
+
+fn is_binary_blob(col: &Column) -> bool {
+    match col.column_type() {
+        MYSQL_TYPE_BLOB | MYSQL_TYPE_LONG_BLOB | MYSQL_TYPE_MEDIUM_BLOB | MYSQL_TYPE_TINY_BLOB => {
+            col.flags().contains(ColumnFlags::BINARY_FLAG)
+        }
+        _ => false,
+    }
+}
+
+// Whether `convert_value` emits the column's values as byte arrays.
+fn emits_bytes(col: &Column, options: &QueryOptions) -> bool {
+    match options.column_kinds.get(&*col.name_str()) {
+        Some(kind) => *kind == ColumnKind::Binary,
+        None => is_binary_blob(col),
+    }
+}
+
+fn bytes_to_json(bytes: &[u8]) -> serde_json::Value {
+    serde_json::Value::Array(
+        bytes
+            .iter()
+            .map(|x| serde_json::Value::Number(Number::from(*x)))
+            .collect(),
+    )
+}
+
+fn convert_value(value: &mysql::Value, col: &Column, options: &QueryOptions) -> serde_json::Value {
+    if let mysql::Value::Bytes(b) = value {
+        match options.column_kinds.get(&*col.name_str()) {
+            Some(ColumnKind::Binary) => return bytes_to_json(b),
+            Some(ColumnKind::Text) => {
+                return serde_json::Value::String(String::from_utf8_lossy(&b).into_owned())
+            }
+            None => {}
+        }
+    }
+    match value {
+        mysql::Value::Bytes(b) => match col.column_type() {
+            MYSQL_TYPE_VARCHAR | MYSQL_TYPE_STRING | MYSQL_TYPE_VAR_STRING => {
+                let text = String::from_utf8_lossy(&b);
+                if options.set_as_array && col.flags().contains(ColumnFlags::SET_FLAG) {
+                    serde_json::Value::Array(
+                        text.split(',')
+                            .filter(|member| !member.is_empty())
+                            .map(|member| serde_json::Value::String(member.to_owned()))
+                            .collect(),
+                    )
+                } else {
+                    serde_json::Value::String(text.into_owned())
+                }
+            }
+            MYSQL_TYPE_BLOB
+            | MYSQL_TYPE_LONG_BLOB
+            | MYSQL_TYPE_MEDIUM_BLOB
+            | MYSQL_TYPE_TINY_BLOB => {
+                if is_binary_blob(col) {
+                    bytes_to_json(b)
+                } else {
+                    serde_json::Value::String(String::from_utf8_lossy(&b).into_owned())
+                }
+            }
+            MYSQL_TYPE_GEOMETRY => match parse_geometry(b) {
+                Some(geometry) => geometry.to_json(options.geometry),
+                None => serde_json::Value::Null,
+            },
+            _ => serde_json::Value::Null,
+        },
+        mysql::Value::Float(f) => {
+            serde_json::Value::Number(Number::from_f64(*f).unwrap_or(Number::from(0)))
+        }
+        mysql::Value::Int(i) => int_to_json(*i, options),
+        mysql::Value::UInt(u) => uint_to_json(*u, options),
+        mysql::Value::Date(year, month, day, _, _, _, _)
+            if col.column_type() == MYSQL_TYPE_DATE || col.column_type() == MYSQL_TYPE_NEWDATE =>
+        {
+            serde_json::Value::String(format!("{}-{:02}-{:02}", year, month, day))
+        }
+        mysql::Value::Date(year, month, day, hour, minute, second, _ms) => {
+            serde_json::Value::String(format!(
+                "{}-{:02}-{:02} {:02}:{:02}:{:02}",
+                year, month, day, hour, minute, second
+            ))
+        }
+        _ => serde_json::Value::Null,
+    }
+}
+
+// Time spent in each phase of a query, filled in when timings are requested.
+#[derive(Default)]
+struct Timings {
+    // Checking the connection out of the pool, including any wait for the
+    // rate limit, plus reconnecting it for a retry after the link was lost.
+    conn_wait: Duration,
+    // Running the statement and reading rows off the wire, including any
+    // failed attempt before a retry.
+    exec: Duration,
+    // Converting rows into JSON values, then writing the result out as
+    // text.
+    serialize: Duration,
+}
+
+// Runs a query on behalf of one of the byond functions, returning either the
+// result or the error as JSON.
+fn query_to_json(query: &str, params: &str, options: &str, job_id: Option<&str>) -> String {
+    match options_from_json(options) {
+        Ok(options) => query_with_options(query, params, options, job_id),
+        Err(e) => err_to_json(e),
+    }
+}
+
+fn query_with_options(
+    query: &str,
+    params: &str,
+    options: QueryOptions,
+    job_id: Option<&str>,
+) -> String {
+    match do_query(query, params, &options, job_id) {
+        Ok(o) => o,
+        Err(e) => {
+            let mut error = error_json(e);
+            if let Some(context) = options.error_context {
+                error["query"] = json!(match context {
+                    ErrorContext::Full => query.to_owned(),
+                    ErrorContext::Redacted => redact_query(query),
+                });
+                if let Some(job_id) = job_id {
+                    error["job_id"] = json!(job_id);
+                }
+            }
+            record_last_error(&error);
+            error.to_string()
+        }
+    }
+}
+
+fn record_last_error(error: &serde_json::Value) {
+    let mut last = error.clone();
+    last["time"] = json!(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs()));
+    if let Ok(mut slot) = LAST_ERROR.write() {
+        *slot = Some(last);
+    }
+}
+
+fn do_query(
+    query: &str,
+    params: &str,
+    options: &QueryOptions,
+    job_id: Option<&str>,
+) -> Result<String, Box<dyn Error>> {
+    use mysql::prelude::Queryable;
+
+    let settings = settings()?;
+    let query: Cow<str> = if options.identifiers.is_empty() {
+        Cow::Borrowed(query)
+    } else {
+        Cow::Owned(substitute_identifiers(query, &options.identifiers)?)
+    };
+    let binding = Binding {
+        coerce_numeric: settings.coerce_numeric_strings,
+        json_documents: options.json_params,
+    };
+    let mut params: serde_json::Value =
+        serde_json::from_str(params).unwrap_or(serde_json::Value::Null);
+    if let Some(ref defaults) = options.param_defaults {
+        params = fill_named_defaults(&query, params, defaults);
+    }
+    let (query, params) = expand_in_lists(&query, params, binding)?;
+    let max_query_length = settings.max_query_length.unwrap_or(1 << 20);
+    if query.len() > max_query_length {
+        return Err(format!(
+            "query is {} bytes long, over the max_query_length of {}",
+            query.len(),
+            max_query_length
+        )
+        .into());
+    }
+    // Queries are run as prepared statements, which the server only allows
+    // to hold one statement. Caught here for a clearer error than the
+    // server's syntax error.
+    if has_multiple_statements(&query) {
+        return Err("query contains multiple statements; run them one at a time".into());
+    }
+    if options.output == OutputFormat::Delimited
+        && (options.group_by.is_some() || options.key_by.is_some())
+    {
+        // Grouped and keyed rows are objects, which delimited output has no
+        // way to write.
+        return Err("delimited output can't be used with group_by or key_by".into());
+    }
+    let mut watch = Stopwatch::start(options.timings);
+    let mut timings = Timings::default();
+    let mut conn = match get_conn()? {
+        Some(conn) => conn,
+        None => return Ok(json!({"status": "offline"}).to_string()),
+    };
+    let checked_out = Instant::now();
+    timings.conn_wait = watch.lap();
+    if let Some(job_id) = job_id {
+        let mut aborts = ABORTS.lock()?;
+        if aborts.cancelled.remove(job_id) {
+            return Err("query was aborted before it started".into());
+        }
+        aborts
+            .running
+            .insert(job_id.to_owned(), conn.connection_id());
+    }
+
+    // Not returned early on failure, so whatever did get changed is still
+    // put back below.
+    let mut saved_variables = None;
+    let mut result = prepare_session(&mut conn, options, &mut saved_variables).and_then(|()| {
+        run_query(
+            &mut conn,
+            &query,
+            params.clone(),
+            options,
+            &mut watch,
+            &mut timings,
+        )
+    });
+    // The pool pings connections as they're checked out, but the link can
+    // still drop between then and the query, such as when the server
+    // reaps it for being idle. A statement that was cut off is retried
+    // once on a fresh connection.
+    let lost = match result {
+        Err(ref e) => e.downcast_ref::<mysql::Error>().map_or(false, is_link_lost),
+        Ok(_) => false,
+    };
+    if lost && (options.retry_writes || is_read_only(&query)) {
+        timings.exec += watch.lap();
+        if let Err(e) = reconnect(&mut conn, &settings) {
+            deregister_job(job_id);
+            return Err(e);
+        }
+        timings.conn_wait += watch.lap();
+        if let Some(job_id) = job_id {
+            // Only fails if the map is poisoned, when the entry couldn't be
+            // removed anyway.
+            let mut aborts = ABORTS.lock()?;
+            if aborts.cancelled.remove(job_id) {
+                aborts.running.remove(job_id);
+                return Err("query was aborted before it was retried".into());
+            }
+            aborts
+                .running
+                .insert(job_id.to_owned(), conn.connection_id());
+        }
+        result = prepare_session(&mut conn, options, &mut saved_variables)
+            .and_then(|()| run_query(&mut conn, &query, params, options, &mut watch, &mut timings));
+        if let Ok(ref mut result) = result {
+            result["retried"] = json!(true);
+        }
+    }
+    // Before anything else runs on the connection and replaces the warnings.
+    let result = match result {
+        Ok(mut result) if options.warnings => fetch_warnings(&mut conn).map(|warnings| {
+            result["warnings"] = warnings;
+            result
+        }),
+        result => result,
+    };
+    let result = match (result, &options.returning) {
+        (Ok(mut result), Some(returning)) => {
+            fetch_returning(&mut conn, returning, options).map(|returned| {
+                result["returned"] = returned;
+                result
+            })
+        }
+        (result, _) => result,
+    };
+    // Before the connection can be reused, so a late sql_abort can't kill
+    // someone else's query.
+    deregister_job(job_id);
+    let role_reset = options.role.is_none() || conn.query_drop("SET ROLE DEFAULT").is_ok();
+    let database_reset = options.database.is_none()
+        || (!settings.default_db.is_empty()
+            && conn
+                .query_drop(format!("USE `{}`", settings.default_db.replace('`', "``")))
+                .is_ok());
+    let variables_reset = match saved_variables {
+        Some(saved) => conn
+            .exec_drop(set_variables_statement(options), saved)
+            .is_ok(),
+        None => true,
+    };
+    if !(role_reset && database_reset && variables_reset) {
+        // Don't return a connection holding the wrong privileges, database
+        // or variables to the pool. Without a connect-time database there's
+        // no way to go back to having none. Should reconnecting fail, the
+        // server's reset still clears what it can, and a connection whose
+        // link is gone is replaced when it's next checked out.
+        if reconnect(&mut conn, &settings).is_err() {
+            let _ = conn.as_mut().reset();
+        }
+    }
+    // Hand the connection back before stringifying, which can be slow for
+    // big results and doesn't need it.
+    std::mem::drop(conn);
+    let held = record_hold(checked_out.elapsed());
+
+    let mut result_json = result?;
+    if let Some(max_hold_ms) = settings.max_hold_ms {
+        if held > Duration::from_millis(max_hold_ms) {
+            // Only a warning: by now the query has run, and an error would
+            // hide that from the caller.
+            result_json["hold_warning"] = json!(format!(
+                "connection was held for {:.0} ms, over the max_hold_ms of {}",
+                duration_to_ms(held),
+                max_hold_ms
+            ));
+        }
+    }
+    // Whatever ran since the last lap isn't one of the phases.
+    watch.lap();
+    let mut output = match options.output {
+        OutputFormat::Json => result_json.to_string(),
+        OutputFormat::Delimited => to_delimited(&result_json),
+    };
+    timings.serialize += watch.lap();
+    if options.timings && options.output == OutputFormat::Json {
+        // Spliced into the text already written so the time it took to
+        // write counts. The result is an object, so the text ends in `}`.
+        let timings = json!({
+            "conn_wait_ms": duration_to_ms(timings.conn_wait),
+            "exec_ms": duration_to_ms(timings.exec),
+            "serialize_ms": duration_to_ms(timings.serialize),
+        });
+        output.pop();
+        output.push_str(",\"timings\":");
+        output.push_str(&timings.to_string());
+        output.push('}');
+    }
+    spill_if_oversized(output, &settings)
+}
+
+// Delimited output is `ok` followed by each row, every row starting with an
+// RS character (ASCII 30) and its cells separated by US characters
+// (ASCII 31), so BYOND can take it apart with splittext. Within a cell, `%`,
+// RS and US are written as `%25`, `%1E` and `%1F`, and NULL is `%00`.
+// Numbers are in decimal, and anything that isn't a scalar (SET members,
+// blob bytes, GeoJSON) is written as its JSON text. Only the rows are kept.
+// Results that aren't rows, like errors or spill references, are still JSON
+// and so start with `{`.
+fn to_delimited(result: &serde_json::Value) -> String {
+    let mut output = String::from("ok");
+    let rows = match result["rows"].as_array() {
+        Some(rows) => rows,
+        None => return output,
+    };
+    for row in rows {
+        output.push('\x1e');
+        for (i, cell) in row.as_array().into_iter().flatten().enumerate() {
+            if i > 0 {
+                output.push('\x1f');
+            }
+            let text = match cell {
+                serde_json::Value::Null => {
+                    output.push_str("%00");
+                    continue;
+                }
+                serde_json::Value::String(s) => Cow::Borrowed(s.as_str()),
+                other => Cow::Owned(other.to_string()),
+            };
+            for c in text.chars() {
+                match c {
+                    '%' => output.push_str("%25"),
+                    '\x1e' => output.push_str("%1E"),
+                    '\x1f' => output.push_str("%1F"),
+                    c => output.push(c),
+                }
+            }
+        }
+    }
+    output
+}
+
+// Applies the connect-time rate limit, if there is one, either waiting for
+// the query's turn or turning it away. Waits are capped at a second, past
+// which the query is turned away regardless.
+fn throttle(mode: RateLimitMode) -> Result<(), Box<dyn Error>> {
+    let wait = {
+        let mut limiter = RATE_LIMITER.lock()?;
+        let bucket = match *limiter {
+            Some(ref mut bucket) => bucket,
+            None => return Ok(()),
+        };
+        let wait = bucket.take();
+        let limit = match mode {
+            RateLimitMode::Reject => Duration::from_secs(0),
+            RateLimitMode::Block => Duration::from_secs(1),
+        };
+        if wait > limit {
+            bucket.give_back();
+            RATE_LIMITED.fetch_add(1, Ordering::Relaxed);
+            return Err(Box::new(RateLimited));
+        }
+        bucket.admitted();
+        wait
+    };
+    if wait > Duration::from_secs(0) {
+        std::thread::sleep(wait);
+    }
+    Ok(())
+}
+
+// Whether a query failed because the link dropped under it, rather than
+// anything about the query, so that running it again on a fresh connection
+// can work. A server that drops an idle connection says so with error 4031
+// (ER_CLIENT_INTERACTION_TIMEOUT, MySQL 8.0.24 and later); older servers
+// just close it, which shows up as an IO or packet error.
+fn is_link_lost(e: &mysql::Error) -> bool {
+    use mysql::DriverError::*;
+
+    match e {
+        mysql::Error::IoError(_) | mysql::Error::CodecError(_) => true,
+        mysql::Error::MySqlError(e) => e.code == 4031,
+        mysql::Error::DriverError(e) => matches!(e, PacketOutOfSync | UnexpectedPacket),
+        _ => false,
+    }
+}
+
+// Swaps the connection for a fresh one from the pool's own settings. Done in
+// place so it goes back to the pool as usual: taking it out with `unwrap`
+// would skip waking callers waiting for a free connection.
+fn reconnect(conn: &mut PooledConn, settings: &ConnectOptions) -> Result<(), Box<dyn Error>> {
+    let opts = settings.opts.clone().ok_or("not connected")?;
+    *conn.as_mut() = Conn::new(opts)?;
+    Ok(())
+}
+
+fn deregister_job(job_id: Option<&str>) {
+    if let Some(job_id) = job_id {
+        if let Ok(mut aborts) = ABORTS.lock() {
+            aborts.running.remove(job_id);
+        }
+    }
+}
+
+// `saved_variables` gets the values session_variables replaces, once
+// they've been read, so they can be put back even if setting them fails.
+fn prepare_session(
+    conn: &mut PooledConn,
+    options: &QueryOptions,
+    saved_variables: &mut Option<Vec<mysql::Value>>,
+) -> Result<(), Box<dyn Error>> {
+    use mysql::prelude::Queryable;
+
+    if !options.session_variables.is_empty() {
+        for name in options.session_variables.keys() {
+            if name.is_empty() || !name.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'_') {
+                return Err(format!("{:?} isn't a valid variable name", name).into());
+            }
+        }
+        let names: Vec<String> = options
+            .session_variables
+            .keys()
+            .map(|name| format!("@@SESSION.{}", name))
+            .collect();
+        // Through the binary protocol, so numbers come back as numbers and
+        // can be set back as such.
+        let saved: mysql::Row = conn
+            .exec_first(format!("SELECT {}", names.join(", ")), ())?
+            .ok_or("reading session variables returned no row")?;
+        *saved_variables = Some(saved.unwrap());
+        let values: Vec<mysql::Value> = options
+            .session_variables
+            .values()
+            .map(|value| {
+                json_to_mysql(
+                    value.clone(),
+                    Binding {
+                        coerce_numeric: false,
+                        json_documents: false,
+                    },
+                )
+            })
+            .collect();
+        conn.exec_drop(set_variables_statement(options), values)?;
+    }
+
+    if let Some(ref role) = options.role {
+        conn.query_drop(format!("SET ROLE {}", quote_identifier(role)?))?;
+    }
+    if let Some(ref database) = options.database {
+        conn.query_drop(format!("USE {}", quote_identifier(database)?))?;
+    }
+    Ok(())
+}
+
+fn set_variables_statement(options: &QueryOptions) -> String {
+    let assignments: Vec<String> = options
+        .session_variables
+        .keys()
+        .map(|name| format!("{} = ?", name))
+        .collect();
+    format!("SET SESSION {}", assignments.join(", "))
+}
+
+fn record_hold(held: Duration) -> Duration {
+    let us = held.as_micros() as u64;
+    HOLD_COUNT.fetch_add(1, Ordering::Relaxed);
+    HOLD_TOTAL_US.fetch_add(us, Ordering::Relaxed);
+    HOLD_MAX_US.fetch_max(us, Ordering::Relaxed);
+    held
+}
+
+fn run_query(
+    conn: &mut PooledConn,
+    query: &str,
+    params: Params,
+    options: &QueryOptions,
+    watch: &mut Stopwatch,
+    timings: &mut Timings,
+) -> Result<serde_json::Value, Box<dyn Error>> {
+    use mysql::prelude::Queryable;
+
+    let mut query_result = conn.exec_iter(query, params)?;
+    let mut rows: Vec<serde_json::Value> = Vec::new();
+    let mut truncated: Vec<serde_json::Value> = Vec::new();
+    let mut json_errors: Vec<serde_json::Value> = Vec::new();
+    // The OK packet these come from is only sent for statements that don't
+    // return rows, and is gone as soon as iteration moves past it, so they
+    // must be read before the loop. A SELECT always reports 0 affected;
+    // INSERT ... SELECT reports the rows it inserted.
+    let affected = query_result.affected_rows();
+    let last_insert_id = query_result.last_insert_id();
+    let info = query_result.info_str().into_owned();
+    let first_set_has_rows = !query_result.columns().as_ref().is_empty();
+    let blob_file = match options.blob_file {
+        Some(ref blob_file) => {
+            let dir = match settings()?.blob_dir {
+                Some(ref dir) => PathBuf::from(dir),
+                None => return Err("blob_file needs blob_dir to be set at connect".into()),
+            };
+            let columns = query_result.columns();
+            let index = |name: &str| {
+                columns
+                    .column_index(name)
+                    .ok_or_else(|| format!("no column named {:?}", name))
+            };
+            Some((
+                dir,
+                index(&blob_file.column)?,
+                index(&blob_file.name_column)?,
+            ))
+        }
+        None => None,
+    };
+    if options.discard_rows {
+        let mut row_count = 0;
+        for row in query_result.by_ref() {
+            row?;
+            row_count += 1;
+        }
+        let result_sets = first_set_has_rows as usize + drain_sets(&mut query_result)?;
+        timings.exec += watch.lap();
+        let mut result = ok_envelope(affected, last_insert_id, row_count, options);
+        check_result_sets(&mut result, result_sets, options)?;
+        return Ok(result);
+    }
+    let key_index = match (&options.group_by, &options.key_by) {
+        (Some(_), Some(_)) => return Err("group_by and key_by can't be used together".into()),
+        (Some(name), None) | (None, Some(name)) => Some(
+            query_result
+                .columns()
+                .column_index(name)
+                .ok_or_else(|| format!("no column named {:?} to key rows by", name))?,
+        ),
+        (None, None) => None,
+    };
+    if options.reject_binary {
+        if let Some(col) = query_result
+            .columns()
+            .as_ref()
+            .iter()
+            .find(|col| emits_bytes(col, options))
+        {
+            return Err(format!("column {:?} is binary", col.name_str()).into());
+        }
+    }
+    let schema_hash = if options.schema_hash {
+        Some(schema_hash(query_result.columns().as_ref()))
+    } else {
+        None
+    };
+    let column_info: Option<Vec<serde_json::Value>> = if options.columns {
+        Some(
+            query_result
+                .columns()
+                .as_ref()
+                .iter()
+                .map(column_json)
+                .collect(),
+        )
+    } else {
+        None
+    };
+    timings.exec += watch.lap();
+    for row in query_result.by_ref() {
+        // Rows are read off the wire lazily, so fetching each one counts as
+        // execution time.
+        let row = row?;
+        timings.exec += watch.lap();
+        let columns = row.columns_ref();
+        let mut json_row: Vec<serde_json::Value> = Vec::new();
+        for i in 0..(row.len()) {
+            let mut converted = match blob_file {
+                Some((ref dir, blob_index, name_index)) if i == blob_index => {
+                    let name = convert_value(&row[name_index], &columns[name_index], options);
+                    write_blob_file(dir, &name, &row[i])?
+                }
+                _ => convert_value(&row[i], &columns[i], options),
+            };
+            if let serde_json::Value::String(ref mut text) = converted {
+                if let Some(transform) = options.transforms.get(&*columns[i].name_str()) {
+                    *text = transform.apply(text);
+                }
+            }
+            let parse_json = options.json_columns.contains(&*columns[i].name_str());
+            if parse_json {
+                if let serde_json::Value::String(ref text) = converted {
+                    match serde_json::from_str(text) {
+                        Ok(parsed) => converted = parsed,
+                        Err(_) => json_errors.push(json!([rows.len(), i])),
+                    }
+                }
+            }
+            if let (Some(limit), serde_json::Value::String(text)) =
+                (options.max_text_length, &mut converted)
+            {
+                // Cutting short a document that didn't parse would only
+                // hide why.
+                if is_text_column(&columns[i]) && !parse_json && truncate_text(text, limit) {
+                    truncated.push(json!([rows.len(), i]));
+                }
+            }
+            if row[i] == mysql::Value::NULL {
+                if let Some(default) = options.null_defaults.get(&*columns[i].name_str()) {
+                    converted = default.clone();
+                }
+            }
+            json_row.push(converted)
+        }
+        rows.push(serde_json::Value::Array(json_row));
+        timings.serialize += watch.lap();
+    }
+
+    let result_sets = first_set_has_rows as usize + drain_sets(&mut query_result)?;
+    timings.exec += watch.lap();
+
+    let mut result = ok_envelope(affected, last_insert_id, rows.len(), options);
+    // Rows whose key is NULL can't be told apart from a "null" string key,
+    // so they go in their own list.
+    let mut null_rows = Vec::new();
+    match key_index {
+        // Groups keep the rows in query order.
+        Some(index) if options.group_by.is_some() => {
+            let mut groups: BTreeMap<String, Vec<serde_json::Value>> = BTreeMap::new();
+            for row in rows {
+                match json_key(&row[index]) {
+                    Some(key) => groups.entry(key).or_default().push(row),
+                    None => null_rows.push(row),
+                }
+            }
+            result["rows"] = json!(groups);
+        }
+        Some(index) => {
+            let mut keyed = Map::new();
+            for row in rows {
+                match json_key(&row[index]) {
+                    Some(key) => {
+                        keyed.insert(key, row);
+                    }
+                    None => null_rows.push(row),
+                }
+            }
+            result["rows"] = serde_json::Value::Object(keyed);
+        }
+        None => result["rows"] = serde_json::Value::Array(rows),
+    }
+    if !null_rows.is_empty() {
+        result["null_rows"] = serde_json::Value::Array(null_rows);
+    }
+    if options.info && !info.is_empty() {
+        result["info"] = serde_json::Value::String(info);
+    }
+    if let Some(column_info) = column_info {
+        result["columns"] = serde_json::Value::Array(column_info);
+    }
+    if let Some(schema_hash) = schema_hash {
+        result["schema_hash"] = json!(schema_hash);
+    }
+    check_result_sets(&mut result, result_sets, options)?;
+    if !truncated.is_empty() {
+        // [row, column] index pairs of each value that was cut short.
+        result["truncated"] = serde_json::Value::Array(truncated);
+    }
+    if !json_errors.is_empty() {
+        // [row, column] index pairs of each json_columns value that was
+        // left as text because it didn't parse.
+        result["json_errors"] = serde_json::Value::Array(json_errors);
+    }
+    Ok(result)
+}
+
+// The fields every function that runs statements reports on success, so
+// results read the same whichever function produced them. Functions add
+// their own fields on top. `last_insert_id` is null when the statement
+// didn't generate one, and `row_count` is the number of rows it returned
+// before any grouping.
+fn ok_envelope(
+    affected: u64,
+    last_insert_id: Option<u64>,
+    row_count: usize,
+    options: &QueryOptions,
+) -> serde_json::Value {
+    json!({
+        "status": "ok",
+        "affected": uint_to_json(affected, options),
+        "last_insert_id": last_insert_id.map(|id| uint_to_json(id, options)),
+        "row_count": row_count,
+    })
+}
+
+// Describes a result column, its type named as in `MYSQL_TYPE_<name>`.
+// The table and database are where the column came from, under their real
+// names rather than any alias, and are null for computed columns.
+fn column_json(col: &Column) -> serde_json::Value {
+    let type_name = format!("{:?}", col.column_type());
+    let non_empty = |name: Cow<str>| {
+        if name.is_empty() {
+            None
+        } else {
+            Some(name.into_owned())
+        }
+    };
+    json!({
+        "name": col.name_str(),
+        "type": type_name.trim_start_matches("MYSQL_TYPE_").to_lowercase(),
+        "nullable": !col.flags().contains(ColumnFlags::NOT_NULL_FLAG),
+        "table": non_empty(col.org_table_str()),
+        "database": non_empty(col.schema_str()),
+    })
+}
+
+// Only the first result set is returned. Stored procedures can produce
+// more, plus a trailing status packet for the CALL itself; read them all
+// off the wire so the connection goes back to the pool in a clean state.
+// Returns how many of them had columns, which leaves out status packets.
+fn drain_sets(query_result: &mut mysql::QueryResult<mysql::Binary>) -> Result<usize, mysql::Error> {
+    let mut with_rows = 0;
+    while let Some(set) = query_result.next_set() {
+        let set = set?;
+        if !set.columns().as_ref().is_empty() {
+            with_rows += 1;
+        }
+        for row in set {
+            row?;
+        }
+    }
+    Ok(with_rows)
+}
+
+// Writes a blob value to `dir`, in a file named after `name`, and returns the
+// path. NULL blobs get no file and stay NULL.
+fn write_blob_file(
+    dir: &Path,
+    name: &serde_json::Value,
+    value: &mysql::Value,
+) -> Result<serde_json::Value, Box<dyn Error>> {
+    let bytes = match value {
+        mysql::Value::Bytes(bytes) => bytes,
+        _ => return Ok(serde_json::Value::Null),
+    };
+    let name = json_key(name).unwrap_or_default();
+    // Keeps the file inside `dir`: no separators, and no `..`.
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+    if !valid {
+        return Err(format!("{:?} can't be used as a blob file name", name).into());
+    }
+    let path = dir.join(name);
+    std::fs::write(&path, bytes)?;
+    Ok(json!(path.to_string_lossy()))
+}
+
+// Compares the number of result sets with columns against the
+// expected_result_sets option, if given.
+fn check_result_sets(
+    result: &mut serde_json::Value,
+    result_sets: usize,
+    options: &QueryOptions,
+) -> Result<(), Box<dyn Error>> {
+    let expected = match options.expected_result_sets {
+        Some(expected) if expected != result_sets => expected,
+        _ => return Ok(()),
+    };
+    let message = format!("expected {} result sets, got {}", expected, result_sets);
+    match options.result_set_mismatch {
+        Mismatch::Warn => result["result_set_warning"] = json!(message),
+        Mismatch::Error => return Err(message.into()),
+    }
+    Ok(())
+}
+
+// 64-bit FNV-1a over each column's name and type, as hex. Stable across
+// runs and builds, unlike std's hasher.
+fn schema_hash(columns: &[Column]) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut feed = |bytes: &[u8]| {
+        for &byte in bytes {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    };
+    for col in columns {
+        feed(col.name_ref());
+        // Separates the name from the type so no two shapes feed the same bytes.
+        feed(&[0, col.column_type() as u8, 0]);
+    }
+    format!("{:016x}", hash)
+}
+
+// The text of a converted value for use as an object key, or None for NULL.
+fn json_key(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(s) => Some(s.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+// Levels are `Note`, `Warning` or `Error`, so callers can ignore the notes.
+fn fetch_warnings(conn: &mut PooledConn) -> Result<serde_json::Value, Box<dyn Error>> {
+    use mysql::prelude::Queryable;
+
+    // Read as raw rows: converting straight to strings panics on a message
+    // that isn't valid UTF-8.
+    let warnings: Vec<mysql::Row> = conn.query("SHOW WARNINGS")?;
+    Ok(warnings
+        .into_iter()
+        .map(|row| {
+            let text = |i| row.as_ref(i).and_then(value_text);
+            json!({
+                "level": text(0),
+                "code": text(1).and_then(|code| code.parse::<u32>().ok()),
+                "message": text(2),
+            })
+        })
+        .collect())
+}
+
+fn fetch_returning(
+    conn: &mut PooledConn,
+    query: &str,
+    options: &QueryOptions,
+) -> Result<serde_json::Value, Box<dyn Error>> {
+    use mysql::prelude::Queryable;
+
+    let rows: Vec<mysql::Row> = conn.exec(query, ())?;
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let columns = row.columns_ref();
+            (0..row.len())
+                .map(|i| convert_value(&row[i], &columns[i], options))
+                .collect::<serde_json::Value>()
+        })
+        .collect())
+}
+
+byond_fn! { sql_query_blocking(query, params, ...rest) {
+    Some(query_to_json(query, params, rest.first().map_or("", |x| &**x), None))
+} }
+
+// Prepares the statement without executing it and reports how many
+// placeholders it expects.
+fn do_param_count(query: &str) -> Result<String, Box<dyn Error>> {
+    use mysql::prelude::Queryable;
+
+    let mut conn = match get_conn()? {
+        Some(conn) => conn,
+        None => return Ok(json!({"status": "offline"}).to_string()),
+    };
+    let stmt = conn.prep(query)?;
+    Ok(json!({
+        "status": "ok",
+        "count": stmt.num_params(),
+    })
+    .to_string())
+}
+
+// Like sql_query_blocking, but every param that is a JSON object or array
+// (positional, or the value of a named one) is bound as its JSON text, for
+// use with JSON columns. `{"__in__": [..]}` lists are still expanded.
+fn json_params_query(query: &str, params: &str, options: &str, job_id: Option<&str>) -> String {
+    match options_from_json(options) {
+        Ok(mut options) => {
+            options.json_params = true;
+            query_with_options(query, params, options, job_id)
+        }
+        Err(e) => err_to_json(e),
+    }
+}
+
+byond_fn! { sql_query_json_params(query, params, ...rest) {
+    Some(json_params_query(query, params, rest.first().map_or("", |x| &**x), None))
+} }
+
+byond_fn! { sql_query_json_params_async(query, params, ...rest) {
+    let query = query.to_owned();
+    let params = params.to_owned();
+    let options = rest.first().map_or(String::new(), |x| x.to_string());
+    Some(start_query_job(move |id| {
+        json_params_query(&query, &params, &options, Some(id))
+    }))
+} }
+
+byond_fn! { sql_param_count(query) {
+    Some(match do_param_count(query) {
+        Ok(o) => o,
+        Err(e) => err_to_json(e)
+    })
+} }
+
+byond_fn! { sql_query_async(query, params, ...rest) {
+    let query = query.to_owned();
+    let params = params.to_owned();
+    let options = rest.first().map_or(String::new(), |x| x.to_string());
+    Some(start_query_job(move |id| {
+        query_to_json(&query, &params, &options, Some(id))
+    }))
+} }
+
+// Runs the statement once per parameter set in `param_sets` (a JSON array),
+// reporting how many rows each run affected, in input order, as
+// `affected_per_set`; `affected` is their total. Slower than one multi-row
+// statement, but shows which conditional updates matched nothing.
+fn do_batch_update(query: &str, param_sets: &str) -> Result<String, Box<dyn Error>> {
+    use mysql::prelude::Queryable;
+
+    let settings = settings()?;
+    let param_sets: Vec<serde_json::Value> = serde_json::from_str(param_sets)?;
+    let mut conn = match get_conn()? {
+        Some(conn) => conn,
+        None => return Ok(json!({"status": "offline"}).to_string()),
+    };
+    let stmt = conn.prep(query)?;
+    let mut affected = Vec::with_capacity(param_sets.len());
+    let mut last_insert_id = None;
+    for params in param_sets {
+        let query_result = conn.exec_iter(
+            &stmt,
+            value_to_params(
+                params,
+                Binding {
+                    coerce_numeric: settings.coerce_numeric_strings,
+                    json_documents: false,
+                },
+            )?,
+        )?;
+        affected.push(query_result.affected_rows());
+        last_insert_id = query_result.last_insert_id().or(last_insert_id);
+    }
+    let mut result = ok_envelope(
+        affected.iter().sum(),
+        last_insert_id,
+        0,
+        &QueryOptions::default(),
+    );
+    result["affected_per_set"] = json!(affected);
+    Ok(result.to_string())
+}
+
+byond_fn! { sql_batch_update_blocking(query, param_sets) {
+    Some(match do_batch_update(query, param_sets) {
+        Ok(o) => o,
+        Err(e) => err_to_json(e)
+    })
+} }
+
+byond_fn! { sql_batch_update_async(query, param_sets) {
+    let query = query.to_owned();
+    let param_sets = param_sets.to_owned();
+    Some(jobs::start(move || {
+        match do_batch_update(&query, &param_sets) {
+            Ok(o) => o,
+            Err(e) => err_to_json(e)
+        }
+    }))
+} }
+
+// Insert sessions stream rows into a table without either side holding the
+// whole import. Rows pushed to a session are buffered, and sent as one
+// multi-row INSERT each time a chunk fills up; finishing sends the rest.
+// Nothing is held on the server between chunks, so a session that's never
+// finished only costs its buffer. Sessions left idle for longer than their
+// idle timeout are closed and their buffered rows discarded, checked
+// whenever one is opened or pushed to.
+struct InsertSession {
+    // The table and column list, quoted ready for the statement.
+    table: String,
+    column_list: String,
+    columns: Vec<String>,
+    chunk_size: usize,
+    idle_timeout: Duration,
+    rows: Vec<Vec<mysql::Value>>,
+    inserted: u64,
+    // From the latest chunk.
+    last_insert_id: Option<u64>,
+    last_used: Instant,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct InsertOptions {
+    // Rows per INSERT. Defaults to 500.
+    chunk_size: Option<usize>,
+    // Seconds a session may sit unused before it's closed. Defaults to 300.
+    idle_timeout: Option<u64>,
+}
+
+// The server's limit on placeholders in one prepared statement.
+const MAX_PLACEHOLDERS: usize = 65535;
+
+static NEXT_INSERT_SESSION: AtomicU64 = AtomicU64::new(1);
+
+impl InsertSession {
+    // Converts param sets, each either an array in column order or an object
+    // keyed by column name, and buffers them.
+    fn buffer(&mut self, param_sets: Vec<serde_json::Value>) -> Result<(), Box<dyn Error>> {
+        let binding = Binding {
+            coerce_numeric: settings()?.coerce_numeric_strings,
+            json_documents: false,
+        };
+        for params in param_sets {
+            let row = match params {
+                serde_json::Value::Array(values) if values.len() == self.columns.len() => values,
+                serde_json::Value::Array(values) => {
+                    return Err(format!(
+                        "param set has {} values for {} columns",
+                        values.len(),
+                        self.columns.len()
+                    )
+                    .into())
+                }
+                serde_json::Value::Object(mut map) => {
+                    let mut row = Vec::with_capacity(self.columns.len());
+                    for column in &self.columns {
+                        match map.remove(column) {
+                            Some(value) => row.push(value),
+                            None => {
+                                return Err(
+                                    format!("param set has no value for {:?}", column).into()
+                                )
+                            }
+                        }
+                    }
+                    row
+                }
+                _ => return Err("param sets must be arrays or objects".into()),
+            };
+            self.rows.push(
+                row.into_iter()
+                    .map(|value| json_to_mysql(value, binding))
+                    .collect(),
+            );
+        }
+        Ok(())
+    }
+
+    // Buffers the param sets and sends each full chunk. Returns false if the
+    // pool is offline, in which case they're kept buffered.
+    fn push(&mut self, param_sets: &str) -> Result<bool, Box<dyn Error>> {
+        self.buffer(serde_json::from_str(param_sets)?)?;
+        while self.rows.len() >= self.chunk_size {
+            if !self.flush(self.chunk_size)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // Sends the first `count` buffered rows. Returns false, keeping them, if
+    // the pool is offline.
+    fn flush(&mut self, count: usize) -> Result<bool, Box<dyn Error>> {
+        use mysql::prelude::Queryable;
+
+        let mut conn = match get_conn()? {
+            Some(conn) => conn,
+            None => return Ok(false),
+        };
+        let row_placeholders = format!("({})", vec!["?"; self.columns.len()].join(", "));
+        let query = format!(
+            "INSERT INTO {} ({}) VALUES {}",
+            self.table,
+            self.column_list,
+            vec![row_placeholders; count].join(", ")
+        );
+        let params: Vec<mysql::Value> = self.rows.drain(..count).flatten().collect();
+        let query_result = conn.exec_iter(query, Params::Positional(params))?;
+        self.inserted += query_result.affected_rows();
+        self.last_insert_id = query_result.last_insert_id().or(self.last_insert_id);
+        Ok(true)
+    }
+}
+
+// Takes a session out of the map while it's used, so flushing one doesn't
+// hold up the others. Idle sessions are swept out first.
+fn take_insert_session(handle: &str) -> Result<InsertSession, Box<dyn Error>> {
+    let mut sessions = INSERT_SESSIONS.lock()?;
+    sessions.retain(|_, session| session.last_used.elapsed() < session.idle_timeout);
+    sessions.remove(handle).ok_or_else(|| {
+        format!(
+            "no insert session {:?}; it may have been closed for being idle",
+            handle
+        )
+        .into()
+    })
+}
+
+fn insert_open(table: &str, columns: &str, options: &str) -> Result<String, Box<dyn Error>> {
+    let options: InsertOptions = if options.is_empty() {
+        InsertOptions::default()
+    } else {
+        serde_json::from_str(options)?
+    };
+    let columns: Vec<String> = serde_json::from_str(columns)?;
+    if columns.is_empty() {
+        return Err("an insert session needs at least one column".into());
+    }
+    let chunk_size = options.chunk_size.unwrap_or(500).max(1);
+    let placeholders = chunk_size.checked_mul(columns.len());
+    if placeholders.map_or(true, |placeholders| placeholders > MAX_PLACEHOLDERS) {
+        return Err(format!(
+            "a chunk of {} rows of {} columns is over the server's limit of {} params",
+            chunk_size,
+            columns.len(),
+            MAX_PLACEHOLDERS
+        )
+        .into());
+    }
+    let quoted: Result<Vec<String>, _> = columns.iter().map(|c| quote_identifier(c)).collect();
+    let session = InsertSession {
+        table: quote_identifier(table)?,
+        column_list: quoted?.join(", "),
+        columns,
+        chunk_size,
+        idle_timeout: Duration::from_secs(options.idle_timeout.unwrap_or(300)),
+        rows: Vec::new(),
+        inserted: 0,
+        last_insert_id: None,
+        last_used: Instant::now(),
+    };
+    let handle = NEXT_INSERT_SESSION
+        .fetch_add(1, Ordering::Relaxed)
+        .to_string();
+    let mut sessions = INSERT_SESSIONS.lock()?;
+    sessions.retain(|_, session| session.last_used.elapsed() < session.idle_timeout);
+    sessions.insert(handle.clone(), session);
+    Ok(json!({
+        "status": "ok",
+        "handle": handle,
+    })
+    .to_string())
+}
+
+// A session that fails to buffer or send rows is closed, though chunks
+// already sent stay inserted; the error reports how many rows that was.
+fn insert_push(handle: &str, param_sets: &str) -> Result<String, Box<dyn Error>> {
+    let mut session = take_insert_session(handle)?;
+    let online = match session.push(param_sets) {
+        Ok(online) => online,
+        Err(e) => return Ok(insert_error(e, &session)),
+    };
+    session.last_used = Instant::now();
+    let result = json!({
+        "status": if online { "ok" } else { "offline" },
+        "buffered": session.rows.len(),
+        "inserted": session.inserted,
+    });
+    INSERT_SESSIONS.lock()?.insert(handle.to_owned(), session);
+    Ok(result.to_string())
+}
+
+fn insert_finish(handle: &str) -> Result<String, Box<dyn Error>> {
+    let mut session = take_insert_session(handle)?;
+    while !session.rows.is_empty() {
+        let count = session.rows.len().min(session.chunk_size);
+        match session.flush(count) {
+            Ok(true) => {}
+            Ok(false) => {
+                // Kept so the rest can be finished once the pool is back.
+                let result = json!({
+                    "status": "offline",
+                    "buffered": session.rows.len(),
+                    "inserted": session.inserted,
+                });
+                session.last_used = Instant::now();
+                INSERT_SESSIONS.lock()?.insert(handle.to_owned(), session);
+                return Ok(result.to_string());
+            }
+            Err(e) => return Ok(insert_error(e, &session)),
+        }
+    }
+    let mut result = ok_envelope(
+        session.inserted,
+        session.last_insert_id,
+        0,
+        &QueryOptions::default(),
+    );
+    result["inserted"] = json!(session.inserted);
+    Ok(result.to_string())
+}
+
+fn insert_error(e: Box<dyn Error>, session: &InsertSession) -> String {
+    let mut error = error_json(e);
+    error["inserted"] = json!(session.inserted);
+    error.to_string()
+}
+
+byond_fn! { sql_insert_open(table, columns, ...rest) {
+    Some(match insert_open(table, columns, rest.first().map_or("", |x| &**x)) {
+        Ok(o) => o,
+        Err(e) => err_to_json(e)
+    })
+} }
+
+byond_fn! { sql_insert_push(handle, param_sets) {
+    Some(match insert_push(handle, param_sets) {
+        Ok(o) => o,
+        Err(e) => err_to_json(e)
+    })
+} }
+
+byond_fn! { sql_insert_finish(handle) {
+    Some(match insert_finish(handle) {
+        Ok(o) => o,
+        Err(e) => err_to_json(e)
+    })
+} }
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ConnectOptions {
+    // Run once after connecting to check that the database is actually
+    // usable, failing the connect if it errors. Defaults to `SELECT 1`.
+    probe: Option<String>,
+    // How many more times to try if the server can't be reached. Failures
+    // that won't fix themselves, like bad credentials, are never retried.
+    retries: u32,
+    // Query results bigger than this many bytes are written to a file in
+    // `spill_dir` (default: the system temp directory) instead of returned.
+    spill_threshold: Option<usize>,
+    spill_dir: Option<String>,
+    // Queries that keep their connection checked out for longer than this
+    // get a `hold_warning` in their result.
+    max_hold_ms: Option<u64>,
+    // Bind string params that are unambiguously numbers, like "42", as
+    // numbers, so comparing them to numeric columns can use an index.
+    coerce_numeric_strings: bool,
+    // At most this many connection checkouts a second, which is one per
+    // query, batch, insert chunk or helper call; those over the limit wait
+    // for their turn or fail with the `rate_limited` category, per the mode.
+    rate_limit: Option<f64>,
+    rate_limit_mode: RateLimitMode,
+    // Collation for string comparisons on every connection, such as
+    // `utf8mb4_general_ci`, rather than whatever the server defaults to.
+    collation: Option<String>,
+    // Queries longer than this many bytes, after expanding identifiers and
+    // IN lists, are refused before being sent. Defaults to 1 MiB.
+    max_query_length: Option<usize>,
+    // Cap on the memory held by reference tables, going by their serialized
+    // size. Loading past it evicts the least recently used tables.
+    reference_memory_limit: Option<usize>,
+    // The only directory the blob_file query option may write to.
+    blob_dir: Option<String>,
+    // The database given to sql_connect_pool, for undoing `database`.
+    #[serde(skip)]
+    default_db: String,
+    // What the pool connects with, for replacing a connection in place.
+    #[serde(skip)]
+    opts: Option<Opts>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum RateLimitMode {
+    Reject,
+    Block,
+}
+
+impl Default for RateLimitMode {
+    fn default() -> RateLimitMode {
+        RateLimitMode::Reject
+    }
+}
+
+fn connect_opts(
+    host: &str,
+    port: u16,
+    user: &str,
+    pass: &str,
+    db: &str,
+    timeout: Duration,
+    init: Vec<String>,
+) -> Opts {
+    OptsBuilder::new()
+        .ip_or_hostname(Some(host))
+        .tcp_port(port)
+        .user(Some(user))
+        .pass(Some(pass))
+        .db_name(Some(db))
+        .read_timeout(Some(timeout))
+        .write_timeout(Some(timeout))
+        // Otherwise connecting to a host that doesn't answer takes as long as
+        // the OS allows, often minutes.
+        .tcp_connect_timeout(Some(timeout).filter(|timeout| *timeout > Duration::from_secs(0)))
+        .init(init)
+        .into()
+}
+
+fn sql_connect(
+    host: &str,
+    port: u16,
+    user: &str,
+    pass: &str,
+    db: &str,
+    timeout: Duration,
+    min_threads: usize,
+    max_threads: usize,
+    options: &str,
+) -> Result<String, Box<dyn Error>> {
+    use mysql::prelude::Queryable;
 
-fn do_query(query: &str, params: &str) -> Result<String, Box<dyn Error>> {
-    let mut conn = {
-        let p = POOL.read()?;
-        let pool = match &*p {
-            Some(s) => s,
-            None => return Ok(json!({"status": "offline"}).to_string()),
-        };
-        pool.get_conn()?
+    let mut options: ConnectOptions = if options.is_empty() {
+        ConnectOptions::default()
+    } else {
+        serde_json::from_str(options)?
     };
-
-    let result_json = {
-        use mysql::prelude::Queryable;
-
-        let query_result = conn.exec_iter(query, params_from_json(params))?;
-        let mut rows: Vec<serde_json::Value> = Vec::new();
-        let affected = query_result.affected_rows();
-        for row in query_result {
-            let row = row?;
-            let columns = row.columns_ref();
-            let mut json_row: Vec<serde_json::Value> = Vec::new();
-            for i in 0..(row.len()) {
-                let col = &columns[i];
-                let ctype = col.column_type();
-                let value = &row[i];
-                let converted = match value {
-                    mysql::Value::Bytes(b) => match ctype {
-                        MYSQL_TYPE_VARCHAR | MYSQL_TYPE_STRING | MYSQL_TYPE_VAR_STRING => {
-                            serde_json::Value::String(String::from_utf8_lossy(&b).into_owned())
-                        }
-                        MYSQL_TYPE_BLOB
-                        | MYSQL_TYPE_LONG_BLOB
-                        | MYSQL_TYPE_MEDIUM_BLOB
-                        | MYSQL_TYPE_TINY_BLOB => {
-                            if col.flags().contains(ColumnFlags::BINARY_FLAG) {
-                                serde_json::Value::Array(
-                                    b.into_iter()
-                                        .map(|x| serde_json::Value::Number(Number::from(*x)))
-                                        .collect(),
-                                )
-                            } else {
-                                serde_json::Value::String(String::from_utf8_lossy(&b).into_owned())
-                            }
-                        }
-                        _ => serde_json::Value::Null,
-                    },
-                    mysql::Value::Float(f) => {
-                        serde_json::Value::Number(Number::from_f64(*f).unwrap_or(Number::from(0)))
-                    }
-                    mysql::Value::Int(i) => serde_json::Value::Number(Number::from(*i)),
-                    mysql::Value::UInt(u) => serde_json::Value::Number(Number::from(*u)),
-                    mysql::Value::Date(year, month, day, hour, minute, second, _ms) => {
-                        serde_json::Value::String(format!(
-                            "{}-{:02}-{:02} {:02}:{:02}:{:02}",
-                            year, month, day, hour, minute, second
-                        ))
-                    }
-                    _ => serde_json::Value::Null,
-                };
-                json_row.push(converted)
+    options.default_db = db.to_owned();
+    let mut init = Vec::new();
+    if let Some(ref collation) = options.collation {
+        // Spliced into the statement, since init statements can't take
+        // parameters. The server rejects names it doesn't know at connect.
+        if collation.is_empty()
+            || !collation
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(format!("invalid collation name: {:?}", collation).into());
+        }
+        init.push(format!("SET collation_connection = '{}'", collation));
+    }
+    let opts = connect_opts(host, port, user, pass, db, timeout, init);
+    options.opts = Some(opts.clone());
+    let mut attempt = 0;
+    let pool = loop {
+        match Pool::new_manual(min_threads, max_threads, opts.clone()) {
+            Ok(pool) => break pool,
+            Err(e) => {
+                if attempt >= options.retries || error_category(&e).0 != "connection" {
+                    return Err(e.into());
+                }
+                attempt += 1;
+                std::thread::sleep(Duration::from_secs(1));
             }
-            rows.push(serde_json::Value::Array(json_row));
         }
-
-        json! {{
-            "status": "ok",
-            "affected": affected,
-            "rows": rows,
-        }}
     };
-    std::mem::drop(conn);
-
-    Ok(result_json.to_string())
+    pool.get_conn()?
+        .query_drop(options.probe.as_deref().unwrap_or("SELECT 1"))?;
+    *RATE_LIMITER.lock()? = options
+        .rate_limit
+        .filter(|&rate| rate > 0.0 && rate.is_finite())
+        .map(TokenBucket::new);
+    *SETTINGS.write()? = Arc::new(options);
+    let mut poolguard = POOL.write()?;
+    *poolguard = Some(pool);
+    Ok(json!({"status": "ok"}).to_string())
 }
 
-byond_fn! { sql_query_blocking(query, params) {
-    Some(match do_query(query, params) {
+byond_fn! { sql_connect_pool(host, port, user, pass, db, timeout, min_threads, max_threads, ...rest) {
+    let port = port.parse::<u16>().unwrap_or(3306);
+    let timeout = Duration::from_secs(timeout.parse::<u64>().unwrap_or(10));
+    let min_threads = min_threads.parse::<usize>().unwrap_or(1);
+    let max_threads = max_threads.parse::<usize>().unwrap_or(50);
+    let options = rest.first().map_or("", |x| &**x);
+    Some(match sql_connect(host, port, user, pass, db, timeout, min_threads, max_threads, options) {
         Ok(o) => o,
         Err(e) => err_to_json(e)
     })
 } }
 
-byond_fn! { sql_query_async(query, params) {
-    let query = query.to_owned();
-    let params = params.to_owned();
+// Connects on a job thread, so a slow database doesn't hold up world
+// startup; the outcome comes back through sql_check_query. Until it finishes
+// queries report `offline` as usual, while sql_connected says `connecting`.
+byond_fn! { sql_connect_async(host, port, user, pass, db, timeout, min_threads, max_threads, ...rest) {
+    let host = host.to_owned();
+    let port = port.parse::<u16>().unwrap_or(3306);
+    let user = user.to_owned();
+    let pass = pass.to_owned();
+    let db = db.to_owned();
+    let timeout = Duration::from_secs(timeout.parse::<u64>().unwrap_or(10));
+    let min_threads = min_threads.parse::<usize>().unwrap_or(1);
+    let max_threads = max_threads.parse::<usize>().unwrap_or(50);
+    let options = rest.first().map_or(String::new(), |x| x.to_string());
+    CONNECTS_PENDING.fetch_add(1, Ordering::SeqCst);
     Some(jobs::start(move || {
-        match do_query(&query, &params) {
+        let result = match sql_connect(&host, port, &user, &pass, &db, timeout, min_threads, max_threads, &options) {
             Ok(o) => o,
             Err(e) => err_to_json(e)
-        }
+        };
+        CONNECTS_PENDING.fetch_sub(1, Ordering::SeqCst);
+        result
     }))
 } }
 
-fn sql_connect(
+// Opens a single connection outside the pool, runs `SELECT 1` and closes it
+// again, for checking settings without touching the connected pool.
+fn test_connection(
     host: &str,
     port: u16,
     user: &str,
     pass: &str,
     db: &str,
     timeout: Duration,
-    min_threads: usize,
-    max_threads: usize,
 ) -> Result<String, Box<dyn Error>> {
-    let builder = OptsBuilder::new()
-        .ip_or_hostname(Some(host))
-        .tcp_port(port)
-        .user(Some(user))
-        .pass(Some(pass))
-        .db_name(Some(db))
-        .read_timeout(Some(timeout))
-        .write_timeout(Some(timeout));
-    let pool = Pool::new_manual(min_threads, max_threads, builder)?;
-    let mut poolguard = POOL.write()?;
-    *poolguard = Some(pool);
+    use mysql::prelude::Queryable;
+
+    let mut conn = mysql::Conn::new(connect_opts(
+        host,
+        port,
+        user,
+        pass,
+        db,
+        timeout,
+        Vec::new(),
+    ))?;
+    conn.query_drop("SELECT 1")?;
     Ok(json!({"status": "ok"}).to_string())
 }
 
-byond_fn! { sql_connect_pool(host, port, user, pass, db, timeout, min_threads, max_threads) {
+byond_fn! { sql_test_connection(host, port, user, pass, db, timeout) {
     let port = port.parse::<u16>().unwrap_or(3306);
     let timeout = Duration::from_secs(timeout.parse::<u64>().unwrap_or(10));
-    let min_threads = min_threads.parse::<usize>().unwrap_or(1);
-    let max_threads = max_threads.parse::<usize>().unwrap_or(50);
-    Some(match sql_connect(host, port, user, pass, db, timeout, min_threads, max_threads) {
+    Some(match test_connection(host, port, user, pass, db, timeout) {
         Ok(o) => o,
         Err(e) => err_to_json(e)
     })
@@ -233,6 +2626,9 @@ byond_fn! { sql_connected() {
                 Some(_) => json!({
                     "status": "online"
                 }).to_string(),
+                None if CONNECTS_PENDING.load(Ordering::SeqCst) > 0 => json!({
+                    "status": "connecting"
+                }).to_string(),
                 None => json!({
                     "status": "offline"
                 }).to_string()
@@ -242,6 +2638,822 @@ byond_fn! { sql_connected() {
     })
 } }
 
+// Starts an async query, registered as one sql_abort can stop before the
+// job can get going. Holding the lock while starting it means the job
+// can't look itself up before it's there.
+fn start_query_job<F: FnOnce(&str) -> String + Send + 'static>(f: F) -> String {
+    let aborts = ABORTS.lock();
+    let id = jobs::start_with_id(move |id| {
+        let output = f(&id);
+        if let Ok(mut aborts) = ABORTS.lock() {
+            aborts.queries.remove(&id);
+            aborts.cancelled.remove(&id);
+        }
+        output
+    });
+    if let Ok(mut aborts) = aborts {
+        aborts.queries.insert(id.clone());
+    }
+    id
+}
+
+// Stops an async query. One that is still waiting for a connection is
+// cancelled before it runs; one that is running is killed on the server,
+// and finishes with the resulting error.
+fn do_abort(id: &str) -> Result<String, Box<dyn Error>> {
+    Ok(json!({
+        "status": "ok",
+        "action": abort_job(id)?,
+    })
+    .to_string())
+}
+
+fn abort_job(id: &str) -> Result<&'static str, Box<dyn Error>> {
+    use mysql::prelude::Queryable;
+
+    match jobs::is_finished(id) {
+        None => return Ok("not-found"),
+        Some(true) => return Ok("already-done"),
+        Some(false) => {}
+    }
+    let connection_id = {
+        let mut aborts = ABORTS.lock()?;
+        match aborts.running.get(id) {
+            Some(&connection_id) => connection_id,
+            None if aborts.queries.contains(id) => {
+                aborts.cancelled.insert(id.to_owned());
+                return Ok("cancelled-queued");
+            }
+            // Batch updates, connects and the like can't be stopped.
+            None => return Ok("not-abortable"),
+        }
+    };
+    // A connection of its own rather than one from the pool: this runs on
+    // BYOND's thread, and the pool could be full of the very queries that
+    // need killing. Made before taking the lock again, so nothing waits on
+    // it meanwhile.
+    let opts = settings()?.opts.clone().ok_or("not connected")?;
+    let mut conn = Conn::new(opts)?;
+    let aborts = ABORTS.lock()?;
+    if aborts.running.get(id) != Some(&connection_id) {
+        return Ok("already-done");
+    }
+    conn.query_drop(format!("KILL QUERY {}", connection_id))?;
+    Ok("killed-running")
+}
+
+byond_fn! { sql_abort(id) {
+    Some(match do_abort(id) {
+        Ok(o) => o,
+        Err(e) => err_to_json(e)
+    })
+} }
+
+// Counts of async jobs still running and of results nobody has collected
+// yet, which pile up if callers forget to check their queries. Jobs have no
+// queue; each one runs on its own thread as soon as it starts.
+byond_fn! { sql_jobs_status() {
+    let status = jobs::status();
+    Some(json!({
+        "status": "ok",
+        "running": status.running,
+        "unclaimed": status.unclaimed,
+        "oldest_ms": status.oldest.map(duration_to_ms),
+    }).to_string())
+} }
+
 byond_fn! { sql_check_query(id) {
     Some(jobs::check(id))
 } }
+
+// Blocks the calling thread for up to `timeout_ms` waiting for the job to
+// finish. Useful for queries that are usually fast, instead of a poll loop.
+byond_fn! { sql_wait_query(id, timeout_ms) {
+    let timeout_ms = timeout_ms.parse::<u64>().unwrap_or(0);
+    let output = jobs::wait(id, Duration::from_millis(timeout_ms));
+    Some(if output != jobs::NO_RESULTS_YET {
+        output
+    } else if timeout_ms == 0 {
+        json!({"status": "pending"}).to_string()
+    } else {
+        json!({"status": "timeout"}).to_string()
+    })
+} }
+
+// Collects the results of every finished job among `ids` (a JSON array) into
+// one object keyed by job id. Jobs still running are left alone and omitted;
+// everything returned is removed from the job store.
+fn drain_results(ids: &str) -> Result<String, Box<dyn Error>> {
+    let ids: Vec<serde_json::Value> = serde_json::from_str(ids)?;
+    let mut results = Map::new();
+    for id in ids {
+        let id = match id {
+            serde_json::Value::String(s) => s,
+            other => other.to_string(),
+        };
+        let output = jobs::check(&id);
+        if output == jobs::NO_RESULTS_YET {
+            continue;
+        }
+        // Query results are JSON already; job system errors are plain text.
+        let value =
+            serde_json::from_str(&output).unwrap_or_else(|_| serde_json::Value::String(output));
+        results.insert(id, value);
+    }
+    Ok(json!({
+        "status": "ok",
+        "results": results,
+    })
+    .to_string())
+}
+
+byond_fn! { sql_drain_results(ids) {
+    Some(match drain_results(ids) {
+        Ok(o) => o,
+        Err(e) => err_to_json(e)
+    })
+} }
+
+// Reports the last error a query ran into, with the Unix time it happened,
+// or null if there hasn't been one.
+byond_fn! { sql_last_error() {
+    Some(match LAST_ERROR.read() {
+        Ok(last) => json!({
+            "status": "ok",
+            "error": *last,
+        }).to_string(),
+        Err(e) => err_to_json(Box::new(e))
+    })
+} }
+
+byond_fn! { sql_stats() {
+    let count = HOLD_COUNT.load(Ordering::Relaxed);
+    let total_us = HOLD_TOTAL_US.load(Ordering::Relaxed);
+    let max_us = HOLD_MAX_US.load(Ordering::Relaxed);
+    let avg_us = if count == 0 { 0 } else { total_us / count };
+    let query_rate = match RATE_LIMITER.lock() {
+        Ok(limiter) => limiter.as_ref().map(TokenBucket::recent_rate),
+        Err(e) => return Some(err_to_json(Box::new(e))),
+    };
+    let (reference_tables, reference_bytes) = match REFERENCES.read() {
+        Ok(references) => (references.len(), references.values().map(|table| table.size).sum::<usize>()),
+        Err(e) => return Some(err_to_json(Box::new(e))),
+    };
+    Some(json!({
+        "status": "ok",
+        "queries": count,
+        "max_hold_ms": max_us as f64 / 1000.0,
+        "avg_hold_ms": avg_us as f64 / 1000.0,
+        // Only tracked while a rate limit is set.
+        "query_rate": query_rate,
+        "rate_limited": RATE_LIMITED.load(Ordering::Relaxed),
+        "reference_tables": reference_tables,
+        "reference_bytes": reference_bytes,
+        // Queries waiting for a connection right now; if this is often above
+        // zero, the pool is saturated and max_threads could be raised.
+        "pool_wait_depth": POOL_WAITING.load(Ordering::SeqCst),
+    }).to_string())
+} }
+
+// What sql_capabilities reports, so DM code can check what this build
+// supports before relying on it. Functions are named without their `sql_`
+// prefix. Every function and option goes in here when it's added; anything
+// missing, such as TLS, named pools or transactions, isn't supported.
+const CAPABILITY_FUNCTIONS: &[&str] = &[
+    "abort",
+    "batch_update_async",
+    "batch_update_blocking",
+    "capabilities",
+    "check_query",
+    "connect_async",
+    "connect_pool",
+    "connected",
+    "disconnect_pool",
+    "drain_results",
+    "insert_finish",
+    "insert_open",
+    "insert_push",
+    "jobs_status",
+    "last_error",
+    "load_reference",
+    "maintain",
+    "param_count",
+    "query_async",
+    "query_blocking",
+    "query_json_params",
+    "query_json_params_async",
+    "reference_lookup",
+    "reference_reload",
+    "server_status",
+    "show",
+    "stats",
+    "table_size",
+    "test_connection",
+    "wait_query",
+];
+
+const CAPABILITY_QUERY_OPTIONS: &[&str] = &[
+    "set_as_array",
+    "info",
+    "timings",
+    "max_text_length",
+    "identifiers",
+    "role",
+    "database",
+    "session_variables",
+    "geometry",
+    "error_context",
+    "transforms",
+    "returning",
+    "big_ints_as_strings",
+    "output",
+    "column_kinds",
+    "json_columns",
+    "null_defaults",
+    "group_by",
+    "key_by",
+    "reject_binary",
+    "discard_rows",
+    "expected_result_sets",
+    "result_set_mismatch",
+    "blob_file",
+    "warnings",
+    "param_defaults",
+    "retry_writes",
+    "columns",
+    "schema_hash",
+];
+
+const CAPABILITY_CONNECT_OPTIONS: &[&str] = &[
+    "probe",
+    "retries",
+    "spill_threshold",
+    "spill_dir",
+    "max_hold_ms",
+    "coerce_numeric_strings",
+    "rate_limit",
+    "rate_limit_mode",
+    "collation",
+    "max_query_length",
+    "reference_memory_limit",
+    "blob_dir",
+];
+
+// Behaviour that comes without a function or option of its own.
+const CAPABILITY_FEATURES: &[&str] = &["in_lists", "retry_on_disconnect"];
+
+byond_fn! { sql_capabilities() {
+    Some(json!({
+        "status": "ok",
+        "version": env!("CARGO_PKG_VERSION"),
+        "functions": CAPABILITY_FUNCTIONS,
+        "query_options": CAPABILITY_QUERY_OPTIONS,
+        "connect_options": CAPABILITY_CONNECT_OPTIONS,
+        "features": CAPABILITY_FEATURES,
+        "output_formats": ["json", "delimited"],
+    }).to_string())
+} }
+
+// The text of a value from a text-protocol query, with any bytes that
+// aren't UTF-8 replaced rather than failing. None for NULL.
+fn value_text(value: &mysql::Value) -> Option<String> {
+    match value {
+        mysql::Value::NULL => None,
+        mysql::Value::Bytes(bytes) => Some(String::from_utf8_lossy(bytes).into_owned()),
+        other => Some(other.as_sql(true)),
+    }
+}
+
+// Reads a column of a row by name as a string, or None if it's NULL or absent.
+fn row_string(row: &mysql::Row, name: &str) -> Option<String> {
+    row.get_opt::<Option<String>, _>(name)
+        .and_then(Result::ok)
+        .and_then(|value| value)
+}
+
+// Wraps the common SHOW commands and normalizes their output, so tooling
+// doesn't depend on column names that vary between server versions.
+fn do_show(kind: &str, arg: &str) -> Result<String, Box<dyn Error>> {
+    use mysql::prelude::Queryable;
+
+    let mut conn = match get_conn()? {
+        Some(conn) => conn,
+        None => return Ok(json!({"status": "offline"}).to_string()),
+    };
+    let data = match kind {
+        // `arg` is an optional database name.
+        "tables" => {
+            let query = if arg.is_empty() {
+                "SHOW TABLES".to_owned()
+            } else {
+                format!("SHOW TABLES FROM {}", quote_identifier(arg)?)
+            };
+            let rows: Vec<mysql::Row> = conn.query(query)?;
+            rows.iter()
+                .filter_map(|row| row.get_opt::<String, _>(0).and_then(Result::ok))
+                .map(serde_json::Value::String)
+                .collect()
+        }
+        // `arg` is the table name.
+        "columns" => {
+            let rows: Vec<mysql::Row> =
+                conn.query(format!("SHOW COLUMNS FROM {}", quote_identifier(arg)?))?;
+            rows.iter()
+                .map(|row| {
+                    json!({
+                        "name": row_string(row, "Field"),
+                        "type": row_string(row, "Type"),
+                        "nullable": row_string(row, "Null").as_deref() == Some("YES"),
+                        "key": row_string(row, "Key"),
+                        "default": row_string(row, "Default"),
+                        "extra": row_string(row, "Extra"),
+                    })
+                })
+                .collect()
+        }
+        // `arg` is the table name. Each index lists its columns in order.
+        "indexes" => {
+            let rows: Vec<mysql::Row> =
+                conn.query(format!("SHOW INDEX FROM {}", quote_identifier(arg)?))?;
+            let mut indexes: Vec<(String, bool, Vec<String>)> = Vec::new();
+            for row in rows.iter() {
+                let name = row_string(row, "Key_name").unwrap_or_default();
+                let column = row_string(row, "Column_name").unwrap_or_default();
+                match indexes.iter_mut().find(|index| index.0 == name) {
+                    Some(index) => index.2.push(column),
+                    None => {
+                        let unique = row_string(row, "Non_unique").as_deref() == Some("0");
+                        indexes.push((name, unique, vec![column]));
+                    }
+                }
+            }
+            indexes
+                .into_iter()
+                .map(|(name, unique, columns)| {
+                    json!({
+                        "name": name,
+                        "unique": unique,
+                        "columns": columns,
+                    })
+                })
+                .collect()
+        }
+        _ => return Err(format!("unknown SHOW kind: {:?}", kind).into()),
+    };
+    let mut result = json!({"status": "ok"});
+    result[kind] = serde_json::Value::Array(data);
+    Ok(result.to_string())
+}
+
+byond_fn! { sql_show(kind, arg) {
+    Some(match do_show(kind, arg) {
+        Ok(o) => o,
+        Err(e) => err_to_json(e)
+    })
+} }
+
+// Runs a table maintenance statement and reports its messages. `ok` is
+// false if any of them is an error.
+fn do_maintain(command: &str, table: &str) -> Result<String, Box<dyn Error>> {
+    use mysql::prelude::Queryable;
+
+    let command = match command {
+        "optimize" => "OPTIMIZE",
+        "analyze" => "ANALYZE",
+        "check" => "CHECK",
+        "repair" => "REPAIR",
+        _ => return Err(format!("unknown maintenance command: {:?}", command).into()),
+    };
+    let query = format!("{} TABLE {}", command, quote_identifier(table)?);
+    let mut conn = match get_conn()? {
+        Some(conn) => conn,
+        None => return Ok(json!({"status": "offline"}).to_string()),
+    };
+    let rows: Vec<mysql::Row> = conn.query(query)?;
+    let mut ok = true;
+    let messages: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| {
+            let msg_type = row_string(row, "Msg_type");
+            if msg_type
+                .as_deref()
+                .map_or(false, |t| t.eq_ignore_ascii_case("error"))
+            {
+                ok = false;
+            }
+            json!({
+                "table": row_string(row, "Table"),
+                "op": row_string(row, "Op"),
+                "type": msg_type,
+                "text": row_string(row, "Msg_text"),
+            })
+        })
+        .collect();
+    Ok(json!({
+        "status": "ok",
+        "ok": ok,
+        "messages": messages,
+    })
+    .to_string())
+}
+
+byond_fn! { sql_maintain(command, table) {
+    Some(match do_maintain(command, table) {
+        Ok(o) => o,
+        Err(e) => err_to_json(e)
+    })
+} }
+
+// A query's result held in memory, so small tables that rarely change can be
+// looked up without going to the database each time.
+struct ReferenceTable {
+    query: String,
+    columns: Vec<String>,
+    rows: Vec<Vec<serde_json::Value>>,
+    // Roughly how much memory the rows take, by their serialized length.
+    size: usize,
+    // REFERENCE_CLOCK when the table was last loaded or looked up, for
+    // evicting the least recently used table when over budget.
+    last_used: AtomicU64,
+}
+
+static REFERENCE_CLOCK: AtomicU64 = AtomicU64::new(0);
+
+// Returns None if the pool is offline.
+fn fetch_reference(query: &str) -> Result<Option<ReferenceTable>, Box<dyn Error>> {
+    use mysql::prelude::Queryable;
+
+    let mut conn = match get_conn()? {
+        Some(conn) => conn,
+        None => return Ok(None),
+    };
+    let options = QueryOptions::default();
+    let mut query_result = conn.exec_iter(query, ())?;
+    let columns: Vec<String> = query_result
+        .columns()
+        .as_ref()
+        .iter()
+        .map(|col| col.name_str().into_owned())
+        .collect();
+    let mut rows = Vec::new();
+    for row in query_result.by_ref() {
+        let row = row?;
+        let row_columns = row.columns_ref();
+        rows.push(
+            (0..row.len())
+                .map(|i| convert_value(&row[i], &row_columns[i], &options))
+                .collect(),
+        );
+    }
+    Ok(Some(ReferenceTable {
+        query: query.to_owned(),
+        columns,
+        size: serde_json::to_string(&rows)?.len(),
+        rows,
+        last_used: AtomicU64::new(REFERENCE_CLOCK.fetch_add(1, Ordering::Relaxed)),
+    }))
+}
+
+fn load_reference(name: &str, query: &str) -> Result<String, Box<dyn Error>> {
+    let table = match fetch_reference(query)? {
+        Some(table) => table,
+        None => return Ok(json!({"status": "offline"}).to_string()),
+    };
+    let count = table.rows.len();
+    let limit = settings()?.reference_memory_limit;
+    if let Some(limit) = limit {
+        if table.size > limit {
+            return Err(format!(
+                "reference table is {} bytes, over the reference_memory_limit of {}",
+                table.size, limit
+            )
+            .into());
+        }
+    }
+    let mut references = REFERENCES.write()?;
+    references.insert(name.to_owned(), table);
+    if let Some(limit) = limit {
+        while references.values().map(|table| table.size).sum::<usize>() > limit {
+            let oldest = references
+                .iter()
+                .filter(|&(other, _)| other != name)
+                .min_by_key(|&(_, table)| table.last_used.load(Ordering::Relaxed))
+                .map(|(other, _)| other.clone());
+            match oldest {
+                Some(oldest) => references.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+    Ok(json!({
+        "status": "ok",
+        "rows": count,
+    })
+    .to_string())
+}
+
+fn reload_reference(name: &str) -> Result<String, Box<dyn Error>> {
+    let query = match REFERENCES.read()?.get(name) {
+        Some(table) => table.query.clone(),
+        None => return Err(format!("no reference table named {:?}", name).into()),
+    };
+    load_reference(name, &query)
+}
+
+// Rows of a loaded table whose `key_column` has the text `key_value`.
+fn lookup_reference(
+    name: &str,
+    key_column: &str,
+    key_value: &str,
+) -> Result<String, Box<dyn Error>> {
+    let references = REFERENCES.read()?;
+    let table = match references.get(name) {
+        Some(table) => table,
+        None => {
+            return Err(format!(
+                "no reference table named {:?}, or it was evicted to stay under \
+                 reference_memory_limit",
+                name
+            )
+            .into())
+        }
+    };
+    table.last_used.store(
+        REFERENCE_CLOCK.fetch_add(1, Ordering::Relaxed),
+        Ordering::Relaxed,
+    );
+    let index = match table.columns.iter().position(|col| col == key_column) {
+        Some(index) => index,
+        None => return Err(format!("no column named {:?} in {:?}", key_column, name).into()),
+    };
+    let rows: Vec<&Vec<serde_json::Value>> = table
+        .rows
+        .iter()
+        .filter(|row| json_key(&row[index]).as_deref() == Some(key_value))
+        .collect();
+    Ok(json!({
+        "status": "ok",
+        "rows": rows,
+    })
+    .to_string())
+}
+
+byond_fn! { sql_load_reference(name, query) {
+    Some(match load_reference(name, query) {
+        Ok(o) => o,
+        Err(e) => err_to_json(e)
+    })
+} }
+
+byond_fn! { sql_reference_reload(name) {
+    Some(match reload_reference(name) {
+        Ok(o) => o,
+        Err(e) => err_to_json(e)
+    })
+} }
+
+byond_fn! { sql_reference_lookup(name, key_column, key_value) {
+    Some(match lookup_reference(name, key_column, key_value) {
+        Ok(o) => o,
+        Err(e) => err_to_json(e)
+    })
+} }
+
+// Approximate size of a table, from information_schema. `table` may be
+// qualified with its database; otherwise the current one is used. Row
+// counts for InnoDB tables are estimates.
+fn do_table_size(table: &str) -> Result<String, Box<dyn Error>> {
+    use mysql::prelude::Queryable;
+
+    let (schema, name) = match table.find('.') {
+        Some(dot) => (Some(&table[..dot]), &table[dot + 1..]),
+        None => (None, table),
+    };
+    let mut conn = match get_conn()? {
+        Some(conn) => conn,
+        None => return Ok(json!({"status": "offline"}).to_string()),
+    };
+    let row: Option<(Option<u64>, Option<u64>, Option<u64>)> = conn.exec_first(
+        "SELECT TABLE_ROWS, DATA_LENGTH, INDEX_LENGTH FROM information_schema.TABLES \
+         WHERE TABLE_SCHEMA = COALESCE(?, DATABASE()) AND TABLE_NAME = ?",
+        (schema, name),
+    )?;
+    let (rows, data_length, index_length) = match row {
+        Some(row) => row,
+        None => return Err(format!("no table named {:?}", table).into()),
+    };
+    Ok(json!({
+        "status": "ok",
+        "rows": rows,
+        "data_bytes": data_length,
+        "index_bytes": index_length,
+    })
+    .to_string())
+}
+
+byond_fn! { sql_table_size(table) {
+    Some(match do_table_size(table) {
+        Ok(o) => o,
+        Err(e) => err_to_json(e)
+    })
+} }
+
+// Reads server status variables from `SHOW GLOBAL STATUS`, or `SESSION` when
+// `scope` says so. `names` is a JSON array; requested names the server
+// doesn't have come back as null. An empty array returns all of them, which
+// is several hundred entries on a modern server.
+fn do_server_status(names: &str, scope: &str) -> Result<String, Box<dyn Error>> {
+    use mysql::prelude::Queryable;
+
+    let names: Vec<String> = serde_json::from_str(names)?;
+    let query = match scope {
+        "" | "global" => "SHOW GLOBAL STATUS",
+        "session" => "SHOW SESSION STATUS",
+        _ => return Err(format!("unknown status scope: {:?}", scope).into()),
+    };
+    let mut conn = match get_conn()? {
+        Some(conn) => conn,
+        None => return Ok(json!({"status": "offline"}).to_string()),
+    };
+    // Read as raw rows, as some servers report NULL or bytes that aren't
+    // UTF-8 for some variables, which converting straight to strings
+    // would panic on.
+    let rows: Vec<mysql::Row> = conn.query(query)?;
+    let mut variables = Map::new();
+    for name in names.iter() {
+        variables.insert(name.clone(), serde_json::Value::Null);
+    }
+    for row in rows {
+        let name = match row.as_ref(0).and_then(value_text) {
+            Some(name) => name,
+            None => continue,
+        };
+        let value = row.as_ref(1).and_then(value_text);
+        // Variable names are case-insensitive; report them as requested.
+        let key = if names.is_empty() {
+            name
+        } else {
+            match names
+                .iter()
+                .find(|wanted| wanted.eq_ignore_ascii_case(&name))
+            {
+                Some(wanted) => wanted.clone(),
+                None => continue,
+            }
+        };
+        variables.insert(key, value.map_or(serde_json::Value::Null, status_value));
+    }
+    Ok(json!({
+        "status": "ok",
+        "variables": variables,
+    })
+    .to_string())
+}
+
+// Status values all come back as text. Those that are plainly numbers are
+// turned into numbers; things like `ON` or version strings are left alone.
+fn status_value(value: String) -> serde_json::Value {
+    if let Ok(i) = value.parse::<i64>() {
+        return json!(i);
+    }
+    if let Ok(u) = value.parse::<u64>() {
+        return json!(u);
+    }
+    match value.parse::<f64>().ok().and_then(Number::from_f64) {
+        Some(n)
+            if value
+                .bytes()
+                .all(|c| c.is_ascii_digit() || c == b'.' || c == b'-') =>
+        {
+            serde_json::Value::Number(n)
+        }
+        _ => serde_json::Value::String(value),
+    }
+}
+
+byond_fn! { sql_server_status(names, ...rest) {
+    Some(match do_server_status(names, rest.first().map_or("", |x| &**x)) {
+        Ok(o) => o,
+        Err(e) => err_to_json(e)
+    })
+} }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_query_spans() {
+        let kinds: Vec<Span> = split_query("SELECT 'a' `b` \"c\" # d\n-- e\n/* f */ /*! g */")
+            .into_iter()
+            .map(|(kind, _)| kind)
+            .filter(|&kind| kind != Span::Code)
+            .collect();
+        assert!(
+            kinds
+                == [
+                    Span::Literal,
+                    Span::Identifier,
+                    Span::Literal,
+                    Span::Comment,
+                    Span::Comment,
+                    Span::Comment,
+                ]
+        );
+    }
+
+    #[test]
+    fn single_statements() {
+        assert!(!has_multiple_statements("SELECT 1"));
+        assert!(!has_multiple_statements("SELECT 1;"));
+        assert!(!has_multiple_statements("SELECT 1; -- done"));
+        assert!(!has_multiple_statements("SELECT 1; /* done */"));
+        assert!(!has_multiple_statements("SELECT 1; # done"));
+        assert!(!has_multiple_statements("SELECT ';'"));
+        assert!(!has_multiple_statements("SELECT \"a;b\""));
+        assert!(!has_multiple_statements("SELECT 'it''s; fine'"));
+        assert!(!has_multiple_statements("SELECT 'it\\'s; fine'"));
+        assert!(!has_multiple_statements("SELECT `a;b` FROM t"));
+        assert!(!has_multiple_statements("SELECT 1 # ; SELECT 2"));
+        assert!(!has_multiple_statements("SELECT 1 -- ; SELECT 2"));
+        assert!(!has_multiple_statements("SELECT 1 /* ; SELECT 2 */"));
+    }
+
+    #[test]
+    fn multiple_statements() {
+        assert!(has_multiple_statements("SELECT 1; SELECT 2"));
+        assert!(has_multiple_statements("SELECT 1;SELECT 2;"));
+        assert!(has_multiple_statements("SELECT 1; 'a'"));
+        assert!(has_multiple_statements("SELECT 'a\\\\'; SELECT 2"));
+        // `--` without whitespace after it isn't a comment.
+        assert!(has_multiple_statements("SELECT 1 --; SELECT 2"));
+        // Executable comments are run, so they count.
+        assert!(has_multiple_statements("SELECT 1 /*! ; SELECT 2 */"));
+    }
+
+    #[test]
+    fn redaction() {
+        assert_eq!(
+            redact_query("SELECT * FROM t WHERE name = 'bob' AND `id2` = 12.5"),
+            "SELECT * FROM t WHERE name = ? AND `id2` = ?"
+        );
+        assert_eq!(
+            redact_query("SELECT x'DEAD', X'BEEF', b'101', N'abc', ax'1', 0xFF"),
+            "SELECT ?, ?, ?, ?, ax?, ?"
+        );
+        assert_eq!(redact_query("SELECT col1 FROM t2"), "SELECT col1 FROM t2");
+        assert_eq!(
+            redact_query("SELECT 1 -- secret\n/* hush */ # shh"),
+            "SELECT ? /* ? */\n/* ? */ /* ? */"
+        );
+        assert_eq!(redact_query("SELECT /*! 'a', 3 */"), "SELECT /*! ?, ? */");
+    }
+
+    #[test]
+    fn numeric_strings() {
+        assert_eq!(numeric_string("42"), Some(mysql::Value::Int(42)));
+        assert_eq!(numeric_string("-7"), Some(mysql::Value::Int(-7)));
+        assert_eq!(numeric_string("1.5"), Some(mysql::Value::Float(1.5)));
+        assert_eq!(
+            numeric_string("18446744073709551615"),
+            Some(mysql::Value::UInt(u64::MAX))
+        );
+        assert_eq!(numeric_string("007"), None);
+        assert_eq!(numeric_string("-0"), None);
+        assert_eq!(numeric_string("1.50"), None);
+        assert_eq!(numeric_string("1e5"), None);
+        assert_eq!(numeric_string("+1"), None);
+        assert_eq!(numeric_string(""), None);
+    }
+
+    const PLAIN: Binding = Binding {
+        coerce_numeric: false,
+        json_documents: false,
+    };
+
+    #[test]
+    fn in_lists_expand() {
+        let (query, params) = expand_in_lists(
+            "SELECT '?' FROM t WHERE x IN (?) AND y = ?",
+            json!([{"__in__": [1, 2]}, 3]),
+            PLAIN,
+        )
+        .unwrap();
+        assert_eq!(query, "SELECT '?' FROM t WHERE x IN (?, ?) AND y = ?");
+        assert_eq!(
+            params,
+            Params::Positional(vec![
+                mysql::Value::UInt(1),
+                mysql::Value::UInt(2),
+                mysql::Value::UInt(3),
+            ])
+        );
+    }
+
+    #[test]
+    fn in_lists_invalid() {
+        let query = "SELECT * FROM t WHERE x IN (?)";
+        assert!(expand_in_lists(query, json!([{"__in__": []}]), PLAIN).is_err());
+        assert!(expand_in_lists(query, json!([{"__in__": 1}]), PLAIN).is_err());
+    }
+}
@@ -6,6 +6,12 @@ fn git() {
     run_dm_tests("git");
 }
 
+#[cfg(feature="sql")]
+#[test]
+fn sql() {
+    run_dm_tests("sql");
+}
+
 fn run_dm_tests(name: &str) {
     std::env::remove_var("RUST_BACKTRACE");
 